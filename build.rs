@@ -0,0 +1,4 @@
+// sqlx::migrate!はコンパイル時にマイグレーションを埋め込むため、変更されたら再ビルドする。
+fn main() {
+    println!("cargo:rerun-if-changed=migrations_sqlite");
+}
@@ -0,0 +1,5 @@
+ALTER TABLE todos
+    ADD COLUMN search_vector tsvector
+    GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED;
+
+CREATE INDEX todos_search_vector_idx ON todos USING GIN (search_vector);
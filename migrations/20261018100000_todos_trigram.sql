@@ -0,0 +1,3 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX todos_text_trgm_idx ON todos USING GIN (text gin_trgm_ops);
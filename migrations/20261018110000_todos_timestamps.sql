@@ -0,0 +1,10 @@
+ALTER TABLE todos
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN completed_at TIMESTAMPTZ;
+
+UPDATE todos SET completed_at = updated_at WHERE completed;
+
+CREATE INDEX todos_created_at_idx ON todos (created_at);
+CREATE INDEX todos_updated_at_idx ON todos (updated_at);
+CREATE INDEX todos_completed_at_idx ON todos (completed_at);
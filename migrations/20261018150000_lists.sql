@@ -0,0 +1,9 @@
+CREATE TABLE lists (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+-- リストの削除はアプリケーション側の方針(restrict/cascade)で行うので、DBでは参照されている間は消せないようにしておく。
+ALTER TABLE todos ADD COLUMN list_id INTEGER REFERENCES lists (id);
+
+CREATE INDEX todos_list_id_idx ON todos (list_id);
@@ -0,0 +1,4 @@
+-- 親を削除するとサブタスクも削除する。
+ALTER TABLE todos ADD COLUMN parent_id INTEGER REFERENCES todos (id) ON DELETE CASCADE;
+
+CREATE INDEX todos_parent_id_idx ON todos (parent_id);
@@ -0,0 +1,3 @@
+-- 繰り返しの規則(RRULEの文字列)と、日付を数えるタイムゾーン(IANAの名前、NULLならUTC)。
+ALTER TABLE todos ADD COLUMN recurrence TEXT;
+ALTER TABLE todos ADD COLUMN time_zone TEXT;
@@ -0,0 +1,4 @@
+-- 削除したTodoはゴミ箱に入れ、deleted_atに日時を入れる。
+ALTER TABLE todos ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX todos_deleted_at_idx ON todos (deleted_at) WHERE deleted_at IS NOT NULL;
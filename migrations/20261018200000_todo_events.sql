@@ -0,0 +1,25 @@
+-- Todoの変更履歴。追記のみで、Todoを完全に削除しても残すため外部キーは張らない。
+CREATE TABLE todo_events (
+    id SERIAL PRIMARY KEY,
+    todo_id INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('created', 'updated', 'deleted', 'restored', 'purged')),
+    old_text TEXT,
+    new_text TEXT,
+    old_completed BOOLEAN,
+    new_completed BOOLEAN,
+    actor TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX todo_events_todo_id_idx ON todo_events (todo_id);
+
+-- 書き込んだ履歴は変更も削除もできない。
+CREATE FUNCTION reject_todo_events_change() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'todo_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER todo_events_append_only
+    BEFORE UPDATE OR DELETE ON todo_events
+    FOR EACH ROW EXECUTE FUNCTION reject_todo_events_change();
@@ -0,0 +1,21 @@
+CREATE VIRTUAL TABLE todos_trigram USING fts5(
+    text,
+    content = 'todos',
+    content_rowid = 'id',
+    tokenize = 'trigram'
+);
+
+CREATE TRIGGER todos_trigram_insert AFTER INSERT ON todos BEGIN
+    INSERT INTO todos_trigram (rowid, text) VALUES (new.id, new.text);
+END;
+
+CREATE TRIGGER todos_trigram_delete AFTER DELETE ON todos BEGIN
+    INSERT INTO todos_trigram (todos_trigram, rowid, text) VALUES ('delete', old.id, old.text);
+END;
+
+CREATE TRIGGER todos_trigram_update AFTER UPDATE OF text ON todos BEGIN
+    INSERT INTO todos_trigram (todos_trigram, rowid, text) VALUES ('delete', old.id, old.text);
+    INSERT INTO todos_trigram (rowid, text) VALUES (new.id, new.text);
+END;
+
+INSERT INTO todos_trigram (todos_trigram) VALUES ('rebuild');
@@ -0,0 +1,14 @@
+-- SQLiteのADD COLUMNは定数しかデフォルトにできないため、追加してから埋める。
+-- 日時はsqlxのchronoと同じRFC3339形式の文字列で保存する。
+ALTER TABLE todos ADD COLUMN created_at TEXT NOT NULL DEFAULT '';
+ALTER TABLE todos ADD COLUMN updated_at TEXT NOT NULL DEFAULT '';
+ALTER TABLE todos ADD COLUMN completed_at TEXT;
+
+UPDATE todos SET
+    created_at = strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now'),
+    updated_at = strftime('%Y-%m-%dT%H:%M:%f+00:00', 'now');
+UPDATE todos SET completed_at = updated_at WHERE completed;
+
+CREATE INDEX todos_created_at_idx ON todos (created_at);
+CREATE INDEX todos_updated_at_idx ON todos (updated_at);
+CREATE INDEX todos_completed_at_idx ON todos (completed_at);
@@ -0,0 +1,25 @@
+-- Todoの変更履歴。追記のみで、Todoを完全に削除しても残すため外部キーは張らない。
+CREATE TABLE todo_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    todo_id INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('created', 'updated', 'deleted', 'restored', 'purged')),
+    old_text TEXT,
+    new_text TEXT,
+    old_completed BOOLEAN,
+    new_completed BOOLEAN,
+    actor TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX todo_events_todo_id_idx ON todo_events (todo_id);
+
+-- 書き込んだ履歴は変更も削除もできない。
+CREATE TRIGGER todo_events_no_update BEFORE UPDATE ON todo_events
+BEGIN
+    SELECT RAISE(ABORT, 'todo_events is append-only');
+END;
+
+CREATE TRIGGER todo_events_no_delete BEFORE DELETE ON todo_events
+BEGIN
+    SELECT RAISE(ABORT, 'todo_events is append-only');
+END;
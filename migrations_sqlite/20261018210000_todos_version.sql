@@ -0,0 +1,2 @@
+-- 楽観的排他制御のためのバージョン。Todoを変更するたびに1つ増やす。
+ALTER TABLE todos ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
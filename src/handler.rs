@@ -1,23 +1,116 @@
-use crate::repositories::{CreateTodo, TodoRepository, TodoRepositoryForDB, UpdateTodo};
+use crate::repositories::{
+    AddDependency, AttachTag, BulkOperation, BulkQuery, ConfirmQuery, CreateList, CreateTag,
+    CreateTodo, DeleteListQuery, IdempotencyRepository, ListRepository, Page, Pagination,
+    RepositoryError, SearchQuery, TagRepository, Todo, TodoDetail, TodoQuery, TodoRepository,
+    TodoTree, UpcomingQuery, UpdateList, UpdateTag, UpdateTodo,
+};
 use actix_web::{
-    delete, get,
-    http::StatusCode,
-    patch, post,
+    body::MessageBody,
+    error::InternalError,
+    get,
+    http::{
+        header::{self, EntityTag, Header, TryIntoHeaderValue},
+        StatusCode,
+    },
+    post,
     web::{self, Json},
-    HttpResponse, Responder,
+    HttpRequest, HttpResponse, Responder, ResponseError,
 };
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tracing::{info_span, instrument};
-use validator::Validate;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::time::SystemTime;
+use tracing::{error, info_span, instrument};
+use validator::{Validate, ValidationError, ValidationErrors};
+
+pub const TOTAL_COUNT: &str = "X-Total-Count";
+pub const NEXT_CURSOR: &str = "X-Next-Cursor";
+// 変更履歴に残す操作した人
+pub const ACTOR: &str = "X-Actor";
+// リトライしても同じTodoを2回作らないためのキー
+pub const IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+// 保存したレスポンスを返した時に付ける
+pub const IDEMPOTENT_REPLAYED: &str = "Idempotent-Replayed";
+// 一括操作で一度に送れる件数
+const BULK_LIMIT: usize = 100;
 
 // 各routerをここて定義する。
-pub fn config(cfg: &mut web::ServiceConfig) {
-    cfg.service(all_todo);
-    cfg.service(create_todo);
-    cfg.service(find_todo);
-    cfg.service(update_todo);
-    cfg.service(delete_todo);
+// ルーティングマクロはジェネリクスに対応していないため、リポジトリを使うrouterはresourceで登録する。
+pub fn config<T: TodoRepository + TagRepository + ListRepository + IdempotencyRepository>(
+    cfg: &mut web::ServiceConfig,
+) {
+    cfg.service(
+        web::resource("/todos")
+            .route(web::get().to(all_todo::<T>))
+            .route(web::post().to(create_todo::<T>))
+            .route(web::patch().to(update_matching_todo::<T>))
+            .route(web::delete().to(delete_matching_todo::<T>)),
+    );
+    // `/todos/{id}`より先に登録する。
+    cfg.service(web::resource("/todos/search").route(web::get().to(search_todo::<T>)));
+    cfg.service(web::resource("/todos/overdue").route(web::get().to(overdue_todo::<T>)));
+    cfg.service(web::resource("/todos/upcoming").route(web::get().to(upcoming_todo::<T>)));
+    cfg.service(web::resource("/todos/bulk").route(web::post().to(bulk_todo::<T>)));
+    cfg.service(
+        web::resource("/todos/{id}")
+            .route(web::get().to(find_todo::<T>))
+            .route(web::patch().to(update_todo::<T>))
+            .route(web::delete().to(delete_todo::<T>)),
+    );
+    cfg.service(web::resource("/todos/{id}/subtree").route(web::get().to(subtree_todo::<T>)));
+    cfg.service(web::resource("/todos/{id}/restore").route(web::post().to(restore_todo::<T>)));
+    cfg.service(web::resource("/todos/{id}/history").route(web::get().to(history_todo::<T>)));
+    cfg.service(
+        web::resource("/todos/{id}/dependencies")
+            .route(web::get().to(all_dependency::<T>))
+            .route(web::post().to(add_dependency::<T>)),
+    );
+    cfg.service(
+        web::resource("/todos/{id}/dependencies/{blocker_id}")
+            .route(web::delete().to(remove_dependency::<T>)),
+    );
+    cfg.service(
+        web::resource("/todos/{id}/tags")
+            .route(web::get().to(all_todo_tag::<T>))
+            .route(web::post().to(attach_todo_tag::<T>)),
+    );
+    cfg.service(
+        web::resource("/todos/{id}/tags/{tag_id}").route(web::delete().to(detach_todo_tag::<T>)),
+    );
+    cfg.service(web::resource("/trash").route(web::get().to(all_trash::<T>)));
+    cfg.service(web::resource("/trash/{id}").route(web::delete().to(purge_trash::<T>)));
+    cfg.service(
+        web::resource("/lists")
+            .route(web::get().to(all_list::<T>))
+            .route(web::post().to(create_list::<T>)),
+    );
+    cfg.service(
+        web::resource("/lists/{id}")
+            .route(web::get().to(find_list::<T>))
+            .route(web::patch().to(update_list::<T>))
+            .route(web::delete().to(delete_list::<T>)),
+    );
+    cfg.service(
+        web::resource("/lists/{id}/todos")
+            .route(web::get().to(all_list_todo::<T>))
+            .route(web::post().to(create_list_todo::<T>)),
+    );
+    cfg.service(
+        web::resource("/tags")
+            .route(web::get().to(all_tag::<T>))
+            .route(web::post().to(create_tag::<T>)),
+    );
+    cfg.service(
+        web::resource("/tags/{id}")
+            .route(web::get().to(find_tag::<T>))
+            .route(web::patch().to(update_tag::<T>))
+            .route(web::delete().to(delete_tag::<T>)),
+    );
     cfg.service(create_user);
+    cfg.app_data(web::JsonConfig::default().error_handler(bad_request_handler));
+    cfg.app_data(web::PathConfig::default().error_handler(bad_request_handler));
+    cfg.app_data(web::QueryConfig::default().error_handler(bad_request_handler));
 }
 
 #[instrument(ret)]
@@ -26,65 +119,947 @@ pub async fn hello_todo() -> impl Responder {
     HttpResponse::Ok().body("Hello actix!!")
 }
 
-#[instrument(ret)]
-#[get("/todos")]
-pub async fn all_todo(repository: web::Data<TodoRepositoryForDB>) -> impl Responder {
-    let todo = repository.all().await.unwrap();
-    HttpResponse::Ok().json(&todo)
+#[instrument(ret, skip(repository))]
+pub async fn all_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(query): web::Query<TodoQuery>,
+    web::Query(pagination): web::Query<Pagination>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    filtered_response(&req, query, pagination, repository.get_ref()).await
+}
+
+// 絞り込んだTodoの一覧をページングして返す。
+async fn filtered_response<T: TodoRepository>(
+    req: &HttpRequest,
+    query: TodoQuery,
+    mut pagination: Pagination,
+    repository: &T,
+) -> Result<HttpResponse, RepositoryError> {
+    query.validate()?;
+    pagination.validate()?;
+    // id以外で並べる場合はcursorが使えないので、offsetでページングする。
+    if !query.sort().is_keyset() {
+        use_offset(
+            &mut pagination,
+            "cursor can only be used when sorting by id",
+        )?;
+    }
+    let page = repository.filter(query, pagination.clone()).await?;
+    Ok(paged_response(req, &pagination, page))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn search_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(query): web::Query<SearchQuery>,
+    web::Query(mut pagination): web::Query<Pagination>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    query.validate()?;
+    pagination.validate()?;
+    // 一致度順に並べるため、offsetでページングする。
+    use_offset(&mut pagination, "cursor can not be used for search")?;
+    let page = repository.search(query, pagination.clone()).await?;
+    Ok(paged_response(&req, &pagination, page))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn overdue_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(mut pagination): web::Query<Pagination>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    pagination.validate()?;
+    // 期限順に並べるため、offsetでページングする。
+    use_offset(&mut pagination, "cursor can not be used for due dates")?;
+    let page = repository.overdue(Utc::now(), pagination.clone()).await?;
+    Ok(paged_response(&req, &pagination, page))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn upcoming_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(query): web::Query<UpcomingQuery>,
+    web::Query(mut pagination): web::Query<Pagination>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    query.validate()?;
+    pagination.validate()?;
+    use_offset(&mut pagination, "cursor can not be used for due dates")?;
+    let page = repository
+        .upcoming(Utc::now(), query.days(), pagination.clone())
+        .await?;
+    Ok(paged_response(&req, &pagination, page))
+}
+
+// `X-Actor`ヘッダーから変更履歴に残す操作した人を取り出す。
+fn actor(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(ACTOR)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|actor| !actor.is_empty())
+        .map(str::to_string)
+}
+
+// TodoのETag。GETとPATCHで同じになるよう、どちらもTodoDetailの内容から作る。
+// `"{version}-{ハッシュ}"`にして、If-Matchではversionの部分だけを比べる。
+fn todo_etag(detail: &TodoDetail) -> EntityTag {
+    let hash = Sha256::digest(serde_json::to_vec(detail).unwrap_or_default());
+    EntityTag::new_strong(format!("{}-{:x}", detail.todo.version, hash))
+}
+
+// Todoに進み具合とブロックされているかを付ける。
+async fn todo_detail<T: TodoRepository>(
+    repository: &T,
+    todo: Todo,
+) -> Result<TodoDetail, RepositoryError> {
+    let progress = repository.progress(todo.id).await?;
+    let blocked = repository
+        .blockers(todo.id)
+        .await?
+        .iter()
+        .any(|blocker| !blocker.completed);
+    Ok(TodoDetail {
+        todo,
+        progress,
+        blocked,
+    })
+}
+
+fn set_etag(resp: &mut HttpResponse, etag: &EntityTag) {
+    if let Ok(value) = header::ETag(etag.clone()).try_into_value() {
+        resp.headers_mut().insert(header::ETAG, value);
+    }
+}
+
+// レスポンスのヘッダーとボディのハッシュから強いETagを作って付ける。
+// ボディがストリームでハッシュを取れない場合はそのまま返す。
+fn tag_response(resp: HttpResponse) -> (HttpResponse, Option<EntityTag>) {
+    let (resp, body) = resp.into_parts();
+    let body = match body.try_into_bytes() {
+        Ok(body) => body,
+        Err(body) => return (resp.set_body(body), None),
+    };
+    // HeaderMapの順番は決まっていないので、並べ替えてからハッシュを取る。
+    let mut headers: Vec<_> = resp
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_bytes()))
+        .collect();
+    headers.sort();
+    let mut hasher = Sha256::new();
+    for (name, value) in headers {
+        hasher.update(name);
+        hasher.update(b":");
+        hasher.update(value);
+        hasher.update(b"\n");
+    }
+    hasher.update(&body);
+    let etag = EntityTag::new_strong(format!("{:x}", hasher.finalize()));
+    let mut resp = resp.set_body(body).map_into_boxed_body();
+    set_etag(&mut resp, &etag);
+    (resp, Some(etag))
+}
+
+// 条件付きGETのレスポンスにする。ETagと、分かる場合はLast-Modifiedを付けて、
+// クライアントが持っているものから変わっていなければボディのない304を返す。
+// ETagを渡さない場合はレスポンスから作る。
+pub fn conditional_response(
+    req: &HttpRequest,
+    mut resp: HttpResponse,
+    etag: Option<EntityTag>,
+    last_modified: Option<DateTime<Utc>>,
+) -> HttpResponse {
+    if !resp.status().is_success() {
+        return resp;
+    }
+    let etag = match etag {
+        Some(etag) => {
+            set_etag(&mut resp, &etag);
+            etag
+        }
+        None => {
+            let (tagged, etag) = tag_response(resp);
+            resp = tagged;
+            let Some(etag) = etag else {
+                return resp;
+            };
+            etag
+        }
+    };
+    if let Some(last_modified) = last_modified {
+        let value = header::LastModified(SystemTime::from(last_modified).into()).try_into_value();
+        if let Ok(value) = value {
+            resp.headers_mut().insert(header::LAST_MODIFIED, value);
+        }
+    }
+    if !not_modified(req, &etag, last_modified) {
+        return resp;
+    }
+    let mut builder = HttpResponse::NotModified();
+    for name in [header::ETAG, header::LAST_MODIFIED] {
+        if let Some(value) = resp.headers().get(&name) {
+            builder.insert_header((name, value.clone()));
+        }
+    }
+    builder.finish()
+}
+
+// If-None-Matchがあればそれだけで決め、なければIf-Modified-SinceとLast-Modifiedを比べる。
+fn not_modified(req: &HttpRequest, etag: &EntityTag, last_modified: Option<DateTime<Utc>>) -> bool {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return match header::IfNoneMatch::parse(req) {
+            Ok(header::IfNoneMatch::Any) => true,
+            Ok(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+            Err(_) => false,
+        };
+    }
+    match (header::IfModifiedSince::parse(req), last_modified) {
+        // HTTPの日時は秒単位なので、秒より下は切り捨てて比べる。
+        (Ok(header::IfModifiedSince(since)), Some(last_modified)) => {
+            let since = DateTime::<Utc>::from(SystemTime::from(since));
+            last_modified.timestamp() <= since.timestamp()
+        }
+        _ => false,
+    }
+}
+
+// If-Matchで指定されたversion。指定がない場合や`*`の場合はNoneで、バージョンを確かめない。
+// 弱いETagや解釈できないETagはどのversionにも一致しないのでPreconditionFailedにする。
+fn if_match(req: &HttpRequest, id: i32) -> Result<Option<i32>, RepositoryError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(None);
+    }
+    match header::IfMatch::parse(req) {
+        Ok(header::IfMatch::Any) => Ok(None),
+        Ok(header::IfMatch::Items(tags)) => tags
+            .iter()
+            .filter(|tag| !tag.weak)
+            .find_map(|tag| tag.tag().split('-').next()?.parse().ok())
+            .map(Some)
+            .ok_or(RepositoryError::PreconditionFailed(id)),
+        Err(_) => Err(RepositoryError::PreconditionFailed(id)),
+    }
+}
+
+// cursorが使えない一覧ではcursorの指定を弾き、offsetでページングする。
+fn use_offset(pagination: &mut Pagination, message: &'static str) -> Result<(), RepositoryError> {
+    if pagination.cursor.is_some() {
+        let mut errors = ValidationErrors::new();
+        let mut error = ValidationError::new("unsupported");
+        error.message = Some(message.into());
+        errors.add("cursor", error);
+        return Err(errors.into());
+    }
+    pagination.offset.get_or_insert(0);
+    Ok(())
+}
+
+// ページングした一覧のレスポンスを作る。
+// ボディは配列のままにして、件数と次ページへのリンクはヘッダーで返す。
+// 一覧は削除されたTodoの分の日時が分からないので、Last-Modifiedは付けずにETagだけで比べる。
+pub fn paged_response<E: Serialize>(
+    req: &HttpRequest,
+    pagination: &Pagination,
+    page: Page<E>,
+) -> HttpResponse {
+    let mut builder = HttpResponse::Ok();
+    builder.insert_header((TOTAL_COUNT, page.total));
+    if let Some(next_cursor) = page.next_cursor {
+        // offset指定の場合はoffsetで、それ以外はcursorで次のページを指す。
+        let next = match pagination.offset {
+            Some(offset) => ("offset", (offset + pagination.limit()).to_string()),
+            None => ("cursor", next_cursor.to_string()),
+        };
+        let mut query: Vec<String> = req
+            .query_string()
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .filter(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                !matches!(key, "limit" | "offset" | "cursor")
+            })
+            .map(str::to_string)
+            .collect();
+        query.push(format!("limit={}", pagination.limit()));
+        query.push(format!("{}={}", next.0, next.1));
+        builder.insert_header((
+            header::LINK,
+            format!("<{}?{}>; rel=\"next\"", req.path(), query.join("&")),
+        ));
+        if pagination.offset.is_none() {
+            builder.insert_header((NEXT_CURSOR, next_cursor));
+        }
+    }
+    conditional_response(req, builder.json(page.items), None, None)
 }
 
 #[instrument(ret, skip(repository))]
-#[post("/todos")]
-pub async fn create_todo(
+pub async fn create_todo<T: TodoRepository + IdempotencyRepository>(
+    req: HttpRequest,
     Json(payload): web::Json<CreateTodo>,
-    repository: web::Data<TodoRepositoryForDB>,
-) -> impl Responder {
-    match payload.validate() {
-        Ok(_) => match repository.create(payload).await {
-            Ok(todo) => HttpResponse::Created().json(todo),
-            Err(_) => HttpResponse::NotFound().finish(),
-        },
-        Err(e) => HttpResponse::BadRequest().body(e.to_string()),
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let Some(key) = idempotency_key(&req)? else {
+        let todo = repository.with_actor(actor(&req)).create(payload).await?;
+        return Ok(HttpResponse::Created().json(todo));
+    };
+
+    // 同じキーのリクエストは、保存したレスポンスをそのまま返す。
+    let request_hash = request_hash(&req, &payload);
+    if let Some(existing) = repository.reserve_key(&key, &request_hash).await? {
+        if existing.request_hash != request_hash {
+            return Err(RepositoryError::IdempotencyKeyReused(key));
+        }
+        let (Some(status), Some(body)) = (existing.status, existing.body) else {
+            return Err(RepositoryError::Conflict(format!(
+                "request with idempotency key {key} is still in progress"
+            )));
+        };
+        let status = u16::try_from(status)
+            .ok()
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .ok_or_else(|| RepositoryError::Unexpected(format!("invalid status {status}")))?;
+        return Ok(HttpResponse::build(status)
+            .content_type(header::ContentType::json())
+            .insert_header((IDEMPOTENT_REPLAYED, "true"))
+            .body(body));
     }
+
+    // 失敗した場合はキーを消して、同じキーでやり直せるようにする。
+    // キーを消せなくても、作れなかった理由の方をエラーとして返す。
+    let todo = match repository.with_actor(actor(&req)).create(payload).await {
+        Ok(todo) => todo,
+        Err(err) => {
+            if let Err(e) = repository.release_key(&key).await {
+                error!("failed to release idempotency key {key}: {e:?}");
+            }
+            return Err(err.into());
+        }
+    };
+    let body =
+        serde_json::to_string(&todo).map_err(|e| RepositoryError::Unexpected(e.to_string()))?;
+    // Todoはもう作ったので、保存に失敗してもエラーにせずに作ったTodoを返す。
+    // キーは処理中のまま残り、保存期間が過ぎるまで同じキーのリトライは409になる。
+    if let Err(e) = repository
+        .save_response(&key, StatusCode::CREATED.as_u16(), &body)
+        .await
+    {
+        error!("failed to save response of idempotency key {key}: {e:?}");
+    }
+    Ok(HttpResponse::Created()
+        .content_type(header::ContentType::json())
+        .body(body))
 }
 
-#[instrument(ret)]
-#[get("/todos/{id}")]
-pub async fn find_todo(
-    id: web::Path<i32>,
-    repository: web::Data<TodoRepositoryForDB>,
-) -> impl Responder {
-    match repository.find(id.into_inner()).await {
-        Ok(v) => HttpResponse::Ok().json(v),
-        Err(_) => HttpResponse::NotFound().finish(),
+// `Idempotency-Key`ヘッダーを取り出す。空や長すぎるキーはエラーにする。
+fn idempotency_key(req: &HttpRequest) -> Result<Option<String>, RepositoryError> {
+    let Some(value) = req.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match value.to_str().map(str::trim) {
+        Ok(key) if (1..=255).contains(&key.len()) => Ok(Some(key.to_string())),
+        _ => {
+            let mut errors = ValidationErrors::new();
+            let mut error = ValidationError::new("length");
+            error.message = Some("Idempotency-Key must be 1 to 255 visible characters".into());
+            errors.add("idempotency_key", error);
+            Err(errors.into())
+        }
     }
 }
 
-#[instrument(ret)]
-#[patch("/todos/{id}")]
-pub async fn update_todo(
+// 同じキーで違うリクエストが来たことを見分けるため、メソッドとパスとボディのハッシュを取る。
+fn request_hash<B: Serialize>(req: &HttpRequest, payload: &B) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update(b" ");
+    hasher.update(req.path());
+    hasher.update(b"\n");
+    hasher.update(serde_json::to_vec(payload).unwrap_or_default());
+    format!("{:x}", hasher.finalize())
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn find_todo<T: TodoRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let todo = repository.find(id.into_inner()).await?;
+    let detail = todo_detail(repository.get_ref(), todo).await?;
+    let (etag, updated_at) = (todo_etag(&detail), detail.todo.updated_at);
+    let resp = HttpResponse::Ok().json(detail);
+    // progressやblockedの変化はupdated_atに出ないので、クライアントにはETagを使ってもらう。
+    Ok(conditional_response(
+        &req,
+        resp,
+        Some(etag),
+        Some(updated_at),
+    ))
+}
+
+// Todoとサブタスクを入れ子にして返す。
+#[instrument(ret, skip(repository))]
+pub async fn subtree_todo<T: TodoRepository>(
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let mut todos = repository.subtree(id.into_inner()).await?;
+    let root = todos.remove(0);
+    Ok(HttpResponse::Ok().json(TodoTree::build(root, todos)))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn update_todo<T: TodoRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    Json(payload): web::Json<UpdateTodo>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let id = id.into_inner();
+    let todo = repository
+        .with_actor(actor(&req))
+        .update(id, if_match(&req, id)?, payload)
+        .await?;
+    // GETと同じETagを返し、そのままIf-None-MatchやIf-Matchに使えるようにする。
+    let etag = todo_etag(&todo_detail(repository.get_ref(), todo.clone()).await?);
+    let mut resp = HttpResponse::Created().json(todo);
+    set_etag(&mut resp, &etag);
+    Ok(resp)
+}
+
+// idのTodoをブロックしているTodoの一覧
+#[instrument(ret, skip(repository))]
+pub async fn all_dependency<T: TodoRepository>(
     id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let blockers = repository.blockers(id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(blockers))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn add_dependency<T: TodoRepository>(
+    id: web::Path<i32>,
+    Json(payload): web::Json<AddDependency>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let blockers = repository
+        .add_blocker(id.into_inner(), payload.blocker_id)
+        .await?;
+    Ok(HttpResponse::Created().json(blockers))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn remove_dependency<T: TodoRepository>(
+    path: web::Path<(i32, i32)>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let (id, blocker_id) = path.into_inner();
+    repository.remove_blocker(id, blocker_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn delete_todo<T: TodoRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let id = id.into_inner();
+    repository
+        .with_actor(actor(&req))
+        .delete(id, if_match(&req, id)?)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// `GET /todos`と同じ条件に合うTodoを全て同じ内容で更新して、更新した件数を返す。
+#[instrument(ret, skip(repository))]
+pub async fn update_matching_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(query): web::Query<TodoQuery>,
+    web::Query(confirm): web::Query<ConfirmQuery>,
     Json(payload): web::Json<UpdateTodo>,
-    repository: web::Data<TodoRepositoryForDB>,
-) -> impl Responder {
-    match repository.update(id.into_inner(), payload).await {
-        Ok(v) => HttpResponse::Created().json(v),
-        Err(_) => HttpResponse::NotFound().finish(),
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    query.validate()?;
+    payload.validate()?;
+    confirmed(&confirm, "set confirm=true to update every matching todo")?;
+    let affected = repository
+        .with_actor(actor(&req))
+        .update_matching(query, payload)
+        .await?;
+    Ok(HttpResponse::Ok().json(Affected { affected }))
+}
+
+// `GET /todos`と同じ条件に合うTodoをサブタスクごとゴミ箱に移して、移した件数を返す。
+#[instrument(ret, skip(repository))]
+pub async fn delete_matching_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(query): web::Query<TodoQuery>,
+    web::Query(confirm): web::Query<ConfirmQuery>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    query.validate()?;
+    confirmed(&confirm, "set confirm=true to delete every matching todo")?;
+    let affected = repository
+        .with_actor(actor(&req))
+        .delete_matching(query)
+        .await?;
+    Ok(HttpResponse::Ok().json(Affected { affected }))
+}
+
+// 条件に合うTodoをまとめて変更する前に、confirm=trueが指定されているかを確かめる。
+fn confirmed(confirm: &ConfirmQuery, message: &'static str) -> Result<(), RepositoryError> {
+    if !confirm.confirm {
+        let mut errors = ValidationErrors::new();
+        let mut error = ValidationError::new("required");
+        error.message = Some(message.into());
+        errors.add("confirm", error);
+        return Err(errors.into());
     }
+    Ok(())
 }
 
-#[instrument(ret)]
-#[delete("/todos/{id}")]
-pub async fn delete_todo(
+// 作成、更新、削除をまとめて1つのトランザクションで実行し、1件ずつの結果を送った順に返す。
+// atomicの場合は1件でも失敗すれば全て取り消し、失敗した操作のステータスで返す。
+// 取り消された操作と実行しなかった操作は424にする。
+#[instrument(ret, skip(repository))]
+pub async fn bulk_todo<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(query): web::Query<BulkQuery>,
+    Json(operations): web::Json<Vec<BulkOperation>>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    if operations.len() > BULK_LIMIT {
+        let mut errors = ValidationErrors::new();
+        let mut error = ValidationError::new("length");
+        error.message = Some(format!("at most {BULK_LIMIT} operations can be sent at once").into());
+        errors.add("operations", error);
+        return Err(errors.into());
+    }
+    let atomic = query.atomic.unwrap_or(true);
+
+    // 先に全件を検証し、不正な操作は実行しない。
+    let statuses: Vec<StatusCode> = operations
+        .iter()
+        .map(|operation| match operation {
+            BulkOperation::Create { .. } => StatusCode::CREATED,
+            BulkOperation::Update { .. } => StatusCode::OK,
+            BulkOperation::Delete { .. } => StatusCode::NO_CONTENT,
+        })
+        .collect();
+    let mut items = Vec::with_capacity(operations.len());
+    let mut valid = Vec::new();
+    for operation in operations {
+        match operation.validate() {
+            Ok(()) => {
+                items.push(None);
+                valid.push(operation);
+            }
+            Err(errors) => items.push(Some(BulkItem::error(&errors.into()))),
+        }
+    }
+    let invalid = items.iter().any(Option::is_some);
+    let results = if atomic && invalid {
+        Vec::new()
+    } else {
+        repository
+            .with_actor(actor(&req))
+            .bulk(valid, atomic)
+            .await?
+    };
+
+    let mut results = results.into_iter();
+    let mut items: Vec<BulkItem> = items
+        .into_iter()
+        .zip(statuses)
+        .map(|(item, status)| {
+            item.unwrap_or_else(|| match results.next() {
+                Some(Ok(todo)) => BulkItem::ok(status, todo),
+                Some(Err(err)) => BulkItem::error(&err),
+                None => BulkItem::failed_dependency(),
+            })
+        })
+        .collect();
+    let failed = items
+        .iter()
+        .filter(|item| item.error.is_some())
+        .map(|item| item.status)
+        .find(|status| *status != StatusCode::FAILED_DEPENDENCY.as_u16());
+    match failed {
+        Some(status) if atomic => {
+            for item in items.iter_mut().filter(|item| item.error.is_none()) {
+                *item = BulkItem::failed_dependency();
+            }
+            let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_REQUEST);
+            Ok(HttpResponse::build(status).json(items))
+        }
+        _ => Ok(HttpResponse::Ok().json(items)),
+    }
+}
+
+// Todoの変更履歴を古い順に返す。完全に削除したTodoの履歴も読める。
+#[instrument(ret, skip(repository))]
+pub async fn history_todo<T: TodoRepository>(
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let events = repository.history(id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(events))
+}
+
+// ゴミ箱のTodoを削除した新しい順に返す。
+#[instrument(ret, skip(repository))]
+pub async fn all_trash<T: TodoRepository>(
+    req: HttpRequest,
+    web::Query(mut pagination): web::Query<Pagination>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    pagination.validate()?;
+    // 削除日時順に並べるため、offsetでページングする。
+    use_offset(&mut pagination, "cursor can not be used for trash")?;
+    let page = repository.trash(pagination.clone()).await?;
+    Ok(paged_response(&req, &pagination, page))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn restore_todo<T: TodoRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let todo = repository
+        .with_actor(actor(&req))
+        .restore(id.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(todo))
+}
+
+// ゴミ箱から完全に削除する。ゴミ箱にないTodoはNotFoundになる。
+#[instrument(ret, skip(repository))]
+pub async fn purge_trash<T: TodoRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    repository
+        .with_actor(actor(&req))
+        .purge(id.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn all_list<T: ListRepository>(
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let lists = repository.all_lists().await?;
+    Ok(HttpResponse::Ok().json(lists))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn create_list<T: ListRepository>(
+    Json(payload): web::Json<CreateList>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let list = repository.create_list(payload).await?;
+    Ok(HttpResponse::Created().json(list))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn find_list<T: ListRepository>(
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let list = repository.find_list(id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn update_list<T: ListRepository>(
+    id: web::Path<i32>,
+    Json(payload): web::Json<UpdateList>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let list = repository.update_list(id.into_inner(), payload).await?;
+    Ok(HttpResponse::Ok().json(list))
+}
+
+// `?policy=cascade`で中のTodoも一緒に削除する。省略した場合はTodoが残っていると409になる。
+#[instrument(ret, skip(repository))]
+pub async fn delete_list<T: TodoRepository + ListRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    web::Query(query): web::Query<DeleteListQuery>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    repository
+        .with_actor(actor(&req))
+        .delete_list(id.into_inner(), query.policy.unwrap_or_default())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// リスト内のTodoの一覧。`GET /todos`と同じ絞り込みとページングが使える。
+#[instrument(ret, skip(repository))]
+pub async fn all_list_todo<T: TodoRepository + ListRepository>(
+    req: HttpRequest,
     id: web::Path<i32>,
-    repository: web::Data<TodoRepositoryForDB>,
-) -> impl Responder {
-    match repository.delete(id.into_inner()).await {
-        Ok(_) => HttpResponse::NoContent().finish(),
-        Err(_) => HttpResponse::NotFound().finish(),
+    web::Query(query): web::Query<TodoQuery>,
+    web::Query(pagination): web::Query<Pagination>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let list = repository.find_list(id.into_inner()).await?;
+    let query = TodoQuery {
+        list_id: Some(list.id),
+        ..query
+    };
+    filtered_response(&req, query, pagination, repository.get_ref()).await
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn create_list_todo<T: TodoRepository>(
+    req: HttpRequest,
+    id: web::Path<i32>,
+    Json(payload): web::Json<CreateTodo>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let payload = CreateTodo {
+        list_id: Some(id.into_inner()),
+        ..payload
+    };
+    let todo = repository.with_actor(actor(&req)).create(payload).await?;
+    Ok(HttpResponse::Created().json(todo))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn all_tag<T: TagRepository>(
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let tags = repository.all_tags().await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn create_tag<T: TagRepository>(
+    Json(payload): web::Json<CreateTag>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let tag = repository.create_tag(payload).await?;
+    Ok(HttpResponse::Created().json(tag))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn find_tag<T: TagRepository>(
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let tag = repository.find_tag(id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(tag))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn update_tag<T: TagRepository>(
+    id: web::Path<i32>,
+    Json(payload): web::Json<UpdateTag>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    payload.validate()?;
+    let tag = repository.update_tag(id.into_inner(), payload).await?;
+    Ok(HttpResponse::Ok().json(tag))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn delete_tag<T: TagRepository>(
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    repository.delete_tag(id.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn all_todo_tag<T: TagRepository>(
+    id: web::Path<i32>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let tags = repository.tags_of(id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(tags))
+}
+
+// タグを付けて、Todoに付いているタグの一覧を返す。
+#[instrument(ret, skip(repository))]
+pub async fn attach_todo_tag<T: TagRepository>(
+    id: web::Path<i32>,
+    Json(payload): web::Json<AttachTag>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let tags = repository
+        .attach_tag(id.into_inner(), payload.tag_id)
+        .await?;
+    Ok(HttpResponse::Created().json(tags))
+}
+
+#[instrument(ret, skip(repository))]
+pub async fn detach_todo_tag<T: TagRepository>(
+    path: web::Path<(i32, i32)>,
+    repository: web::Data<T>,
+) -> Result<HttpResponse, RepositoryError> {
+    let (id, tag_id) = path.into_inner();
+    repository.detach_tag(id, tag_id).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+// エラー時のレスポンスボディ。どのエラーもこの形で返す。
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct ErrorResponse {
+    pub code: String,
+    pub message: String,
+    pub details: Option<serde_json::Value>,
+}
+
+// まとめて変更した件数
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub struct Affected {
+    pub affected: u64,
+}
+
+// 一括操作1件の結果。成功した場合はTodo(削除の場合はなし)を、失敗した場合はエラーを返す。
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct BulkItem {
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub todo: Option<Todo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorResponse>,
+}
+
+impl BulkItem {
+    fn ok(status: StatusCode, todo: Option<Todo>) -> Self {
+        BulkItem {
+            status: status.as_u16(),
+            todo,
+            error: None,
+        }
+    }
+
+    fn error(err: &RepositoryError) -> Self {
+        BulkItem {
+            status: err.status_code().as_u16(),
+            todo: None,
+            error: Some(err.into()),
+        }
+    }
+
+    fn failed_dependency() -> Self {
+        BulkItem {
+            status: StatusCode::FAILED_DEPENDENCY.as_u16(),
+            todo: None,
+            error: Some(ErrorResponse {
+                code: "failed_dependency".to_string(),
+                message: "not applied because another operation failed".to_string(),
+                details: None,
+            }),
+        }
     }
 }
 
+fn error_response(
+    status: StatusCode,
+    code: &str,
+    message: String,
+    details: Option<serde_json::Value>,
+) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse {
+        code: code.to_string(),
+        message,
+        details,
+    })
+}
+
+impl ResponseError for RepositoryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RepositoryError::NotFound(_) => StatusCode::NOT_FOUND,
+            RepositoryError::Conflict(_) => StatusCode::CONFLICT,
+            RepositoryError::Validation(_) => StatusCode::BAD_REQUEST,
+            RepositoryError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RepositoryError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            RepositoryError::IdempotencyKeyReused(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RepositoryError::Unexpected(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(ErrorResponse::from(self))
+    }
+}
+
+impl From<&RepositoryError> for ErrorResponse {
+    fn from(err: &RepositoryError) -> Self {
+        let (code, message, details) = match err {
+            RepositoryError::NotFound(id) => {
+                ("not_found", err.to_string(), Some(json!({ "id": id })))
+            }
+            RepositoryError::Conflict(_) => ("conflict", err.to_string(), None),
+            RepositoryError::PreconditionFailed(id) => (
+                "precondition_failed",
+                err.to_string(),
+                Some(json!({ "id": id })),
+            ),
+            RepositoryError::IdempotencyKeyReused(key) => (
+                "idempotency_key_reused",
+                err.to_string(),
+                Some(json!({ "key": key })),
+            ),
+            RepositoryError::Validation(errors) => (
+                "validation_failed",
+                "Validation Error".to_string(),
+                serde_json::to_value(errors).ok(),
+            ),
+            // 内部の情報は返さずにログにだけ残す。
+            RepositoryError::Unavailable(_) => {
+                error!("{err}");
+                ("unavailable", "Service Unavailable".to_string(), None)
+            }
+            RepositoryError::Unexpected(_) => {
+                error!("{err}");
+                ("unexpected", "Unexpected Error".to_string(), None)
+            }
+        };
+        ErrorResponse {
+            code: code.to_string(),
+            message,
+            details,
+        }
+    }
+}
+
+// リクエストボディやパスの読み取りに失敗した場合も、同じ形のエラーを返す。
+fn bad_request_handler<E: std::fmt::Display + std::fmt::Debug + 'static>(
+    err: E,
+    _req: &HttpRequest,
+) -> actix_web::Error {
+    let response = error_response(
+        StatusCode::BAD_REQUEST,
+        "bad_request",
+        err.to_string(),
+        None,
+    );
+    InternalError::from_response(err, response).into()
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub struct CreateUser {
     pub username: String,
@@ -105,3 +1080,1903 @@ async fn create_user(payload: web::Json<CreateUser>) -> impl Responder {
     let _span = info_span!("request userdata: ", "{:?}", user).entered();
     HttpResponse::Ok().status(StatusCode::CREATED).json(user)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::{
+        Priority, Progress, Tag, Todo, TodoEvent, TodoEventKind, TodoList, TodoRepositoryForMemory,
+        TodoSearchHit,
+    };
+    use actix_web::{
+        dev::ServiceResponse,
+        http::{header::ContentType, StatusCode},
+        test, App,
+    };
+    use chrono::{Duration, SecondsFormat};
+    use pretty_assertions::assert_eq;
+
+    #[actix_web::test]
+    async fn should_created_todo() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("should_return_created_todo".to_string()))
+            .to_request();
+
+        let resp: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            Todo {
+                created_at: resp.created_at,
+                updated_at: resp.updated_at,
+                ..Todo::new(1, "should_return_created_todo".to_string())
+            },
+            resp
+        );
+    }
+
+    #[actix_web::test]
+    async fn should_find_todo() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("should_find_todo".to_string()))
+            .to_request();
+        let expected: Todo = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let resp: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(expected, resp);
+    }
+
+    #[actix_web::test]
+    async fn should_get_all_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("should_get_all_todos".to_string()))
+            .to_request();
+        let expected: Todo = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let resp: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![expected], resp);
+    }
+
+    #[actix_web::test]
+    async fn should_update_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("before_update_todos".to_string()))
+            .to_request();
+        let created: Todo = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                text: Some("should_update_todos".to_string()),
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        let resp: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            Todo {
+                text: "should_update_todos".to_string(),
+                completed: true,
+                updated_at: resp.updated_at,
+                completed_at: resp.completed_at,
+                version: 2,
+                ..created
+            },
+            resp
+        );
+        assert!(resp.completed_at.is_some());
+    }
+
+    #[actix_web::test]
+    async fn should_delete_todo() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("should_delete_todos".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::delete().uri("/todos/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+
+        let req = test::TestRequest::delete().uri("/todos/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_trash_and_restore_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        // 1の下に2、2の下に3
+        for (text, parent_id) in [("move", None), ("pack", Some(1)), ("buy boxes", Some(2))] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let ids = |todos: Vec<Todo>| todos.iter().map(|todo| todo.id).collect::<Vec<_>>();
+
+        // 削除するとサブタスクごとゴミ箱に入り、一覧や取得からは見えなくなる
+        let req = test::TestRequest::delete().uri("/todos/2").to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1], ids(todos));
+        let req = test::TestRequest::get().uri("/todos/3").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let req = test::TestRequest::get().uri("/trash").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!("2", resp.headers().get(TOTAL_COUNT).unwrap());
+        let todos: Vec<Todo> = test::read_body_json(resp).await;
+        assert!(todos.iter().all(|todo| todo.deleted_at.is_some()));
+        let req = test::TestRequest::get().uri("/trash?cursor=1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+
+        // 親がゴミ箱にある間はサブタスクだけを戻せない
+        let restore = |id: i32| {
+            test::TestRequest::post()
+                .uri(&format!("/todos/{id}/restore"))
+                .to_request()
+        };
+        let resp = test::call_service(&app, restore(3)).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+        let resp = test::call_service(&app, restore(1)).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        let todo: Todo = test::call_and_read_body_json(&app, restore(2)).await;
+        assert_eq!(None, todo.deleted_at);
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![3, 2, 1], ids(todos));
+
+        // ゴミ箱から完全に削除する。ゴミ箱にないTodoは消せない
+        let purge = |id: i32| {
+            test::TestRequest::delete()
+                .uri(&format!("/trash/{id}"))
+                .to_request()
+        };
+        let resp = test::call_service(&app, purge(1)).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let req = test::TestRequest::delete().uri("/todos/1").to_request();
+        test::call_service(&app, req).await;
+        let resp = test::call_service(&app, purge(1)).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+        let req = test::TestRequest::get().uri("/trash").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert!(todos.is_empty());
+        let resp = test::call_service(&app, restore(1)).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_return_not_found_error() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!(
+            ErrorResponse {
+                code: "not_found".to_string(),
+                message: "NotFound, id is 1".to_string(),
+                details: Some(serde_json::json!({ "id": 1 })),
+            },
+            body
+        );
+    }
+
+    #[actix_web::test]
+    async fn should_return_validation_error() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("".to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!("validation_failed", body.code);
+        assert!(body.details.unwrap().get("text").is_some());
+
+        // JSONとして読めない場合も同じ形で返す
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_payload("{")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let body: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!("bad_request", body.code);
+    }
+
+    #[actix_web::test]
+    async fn should_map_repository_error_status() {
+        let err = RepositoryError::from(anyhow::Error::from(RepositoryError::NotFound(1)));
+        assert_eq!(StatusCode::NOT_FOUND, err.status_code());
+
+        let err = RepositoryError::from(anyhow::Error::from(sqlx::Error::PoolTimedOut));
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, err.status_code());
+
+        let err = RepositoryError::from(anyhow::anyhow!("boom"));
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, err.status_code());
+
+        let err = RepositoryError::IdempotencyKeyReused("retry-1".to_string());
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, err.status_code());
+    }
+
+    #[actix_web::test]
+    async fn should_paginate_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for i in 1..=5 {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(format!("todo {i}")))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let req = test::TestRequest::get().uri("/todos?limit=2").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!("5", resp.headers().get(TOTAL_COUNT).unwrap());
+        assert_eq!("4", resp.headers().get(NEXT_CURSOR).unwrap());
+        assert_eq!(
+            "</todos?limit=2&cursor=4>; rel=\"next\"",
+            resp.headers().get(header::LINK).unwrap()
+        );
+        let todos: Vec<Todo> = test::read_body_json(resp).await;
+        assert_eq!(vec![5, 4], todos.iter().map(|t| t.id).collect::<Vec<_>>());
+
+        // 最後のページにはリンクが付かない
+        let req = test::TestRequest::get()
+            .uri("/todos?limit=2&cursor=2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.headers().get(header::LINK).is_none());
+        let todos: Vec<Todo> = test::read_body_json(resp).await;
+        assert_eq!(vec![1], todos.iter().map(|t| t.id).collect::<Vec<_>>());
+
+        let req = test::TestRequest::get()
+            .uri("/todos?limit=2&offset=2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            "</todos?limit=2&offset=4>; rel=\"next\"",
+            resp.headers().get(header::LINK).unwrap()
+        );
+        let todos: Vec<Todo> = test::read_body_json(resp).await;
+        assert_eq!(vec![3, 2], todos.iter().map(|t| t.id).collect::<Vec<_>>());
+
+        let req = test::TestRequest::get()
+            .uri("/todos?offset=1&cursor=3")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_filter_and_sort_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for text in ["buy milk", "Milk tea", "bread", "100% juice"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(text.to_string()))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let req = test::TestRequest::get()
+            .uri("/todos?completed=false&q=milk")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![2], ids(todos));
+
+        let req = test::TestRequest::get().uri("/todos?q=%25").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![4], ids(todos));
+
+        let req = test::TestRequest::get().uri("/todos?sort=id").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1, 2, 3, 4], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos?sort=-completed&limit=2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            "</todos?sort=-completed&limit=2&offset=2>; rel=\"next\"",
+            resp.headers().get(header::LINK).unwrap()
+        );
+        let todos: Vec<Todo> = test::read_body_json(resp).await;
+        assert_eq!(vec![1, 4], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos?sort=text&cursor=2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+
+        let req = test::TestRequest::get()
+            .uri("/todos?sort=color")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_search_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for text in ["buy milk", "bread", "milk tea"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(text.to_string()))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/todos/search?q=milk&limit=1")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("2", resp.headers().get(TOTAL_COUNT).unwrap());
+        assert_eq!(
+            "</todos/search?q=milk&limit=1&offset=1>; rel=\"next\"",
+            resp.headers().get(header::LINK).unwrap()
+        );
+        let hits: Vec<TodoSearchHit> = test::read_body_json(resp).await;
+        assert_eq!(1, hits.len());
+        assert_eq!("<mark>milk</mark> tea", hits[0].snippet);
+
+        let req = test::TestRequest::get()
+            .uri("/todos/search?q=")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_filter_and_sort_by_dates() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let mut created = vec![];
+        for text in ["first", "second", "third"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(text.to_string()))
+                .to_request();
+            let todo: Todo = test::call_and_read_body_json(&app, req).await;
+            created.push(todo);
+        }
+        for id in [3, 1] {
+            let req = test::TestRequest::patch()
+                .uri(&format!("/todos/{id}"))
+                .insert_header(ContentType::json())
+                .set_json(UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        // 完了日時の新しい順。未完了のものは最後
+        let req = test::TestRequest::get()
+            .uri("/todos?sort=-completed_at")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1, 3, 2], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos?sort=created_at")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1, 2, 3], ids(todos));
+
+        let created_after = created[1]
+            .created_at
+            .to_rfc3339_opts(SecondsFormat::Micros, true);
+        let req = test::TestRequest::get()
+            .uri(&format!("/todos?created_after={created_after}&sort=id"))
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![2, 3], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos?created_after=yesterday")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_list_overdue_and_upcoming_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let now = Utc::now();
+        let due_dates = [
+            Some(now - Duration::days(2)),
+            Some(now + Duration::days(3)),
+            Some(now + Duration::days(10)),
+            None,
+            Some(now - Duration::days(1)),
+        ];
+        for (i, due_at) in due_dates.into_iter().enumerate() {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    due_at,
+                    ..CreateTodo::new(format!("todo {i}"))
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        // 完了したものは期限切れにならない
+        let req = test::TestRequest::patch()
+            .uri("/todos/5")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let req = test::TestRequest::get().uri("/todos/overdue").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1], ids(todos));
+
+        let req = test::TestRequest::get().uri("/todos/upcoming").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![2], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos/upcoming?days=30")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![2, 3], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos/upcoming?days=0")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_set_and_clear_due_at() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        // タイムゾーン付きで受け取り、UTCで返す
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"text":"due","due_at":"2026-10-20T09:00:00+09:00"}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(Some("2026-10-20T00:00:00Z".parse().unwrap()), todo.due_at);
+
+        // 省略した場合は変わらない
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"text":"renamed"}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert!(todo.due_at.is_some());
+
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"due_at":null}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(None, todo.due_at);
+
+        for payload in [
+            r#"{"text":"due","due_at":"2026-13-01T00:00:00Z"}"#,
+            r#"{"text":"due","due_at":"2026-10-20"}"#,
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_payload(payload)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        }
+    }
+
+    #[actix_web::test]
+    async fn should_filter_and_sort_by_priority() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for priority in [
+            Priority::High,
+            Priority::None,
+            Priority::Urgent,
+            Priority::Low,
+            Priority::High,
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    priority,
+                    ..CreateTodo::new(format!("{priority:?}"))
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let req = test::TestRequest::get()
+            .uri("/todos?sort=-priority")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![3, 5, 1, 4, 2], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos?priority=high")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![5, 1], ids(todos));
+
+        let req = test::TestRequest::get()
+            .uri("/todos?min_priority=medium&sort=id")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1, 3, 5], ids(todos));
+
+        let req = test::TestRequest::patch()
+            .uri("/todos/2")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"priority":"medium"}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(Priority::Medium, todo.priority);
+
+        // 存在しない優先度は受け付けない
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"text":"[!!]","priority":"critical"}"#)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+
+        let req = test::TestRequest::get()
+            .uri("/todos?priority=2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_manage_tags_and_filter_by_tag() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for text in ["buy milk", "write report", "call mom"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(text.to_string()))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        for name in ["work", "home"] {
+            let req = test::TestRequest::post()
+                .uri("/tags")
+                .insert_header(ContentType::json())
+                .set_json(CreateTag::new(name.to_string()))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::CREATED, resp.status());
+        }
+
+        // 同じ名前のタグは作れない
+        let req = test::TestRequest::post()
+            .uri("/tags")
+            .insert_header(ContentType::json())
+            .set_json(CreateTag::new("work".to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+
+        for (todo_id, tag_id) in [(1, 2), (2, 1), (3, 2), (3, 1)] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/todos/{todo_id}/tags"))
+                .insert_header(ContentType::json())
+                .set_json(AttachTag { tag_id })
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::CREATED, resp.status());
+        }
+
+        let req = test::TestRequest::get().uri("/todos/3/tags").to_request();
+        let tags: Vec<Tag> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            vec![
+                Tag {
+                    id: 2,
+                    name: "home".to_string()
+                },
+                Tag {
+                    id: 1,
+                    name: "work".to_string()
+                },
+            ],
+            tags
+        );
+
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+        let req = test::TestRequest::get().uri("/todos?tag=home").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![3, 1], ids(todos));
+
+        let req = test::TestRequest::delete()
+            .uri("/todos/3/tags/2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+        let req = test::TestRequest::delete()
+            .uri("/todos/3/tags/2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        // 名前を変えると絞り込みにも反映される
+        let req = test::TestRequest::patch()
+            .uri("/tags/2")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTag {
+                name: "private".to_string(),
+            })
+            .to_request();
+        let tag: Tag = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("private", tag.name);
+        let req = test::TestRequest::get()
+            .uri("/todos?tag=private")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1], ids(todos));
+
+        // タグを消すとTodoからも外れる
+        let req = test::TestRequest::delete().uri("/tags/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+        let req = test::TestRequest::get().uri("/todos?tag=work").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert!(todos.is_empty());
+
+        let req = test::TestRequest::get().uri("/tags").to_request();
+        let tags: Vec<Tag> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            vec!["private"],
+            tags.iter().map(|t| &t.name).collect::<Vec<_>>()
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/todos/99/tags")
+            .insert_header(ContentType::json())
+            .set_json(AttachTag { tag_id: 2 })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_group_todos_by_list() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for name in ["work", "home"] {
+            let req = test::TestRequest::post()
+                .uri("/lists")
+                .insert_header(ContentType::json())
+                .set_json(CreateList::new(name.to_string()))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::CREATED, resp.status());
+        }
+        for text in ["write report", "review"] {
+            let req = test::TestRequest::post()
+                .uri("/lists/1/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(text.to_string()))
+                .to_request();
+            let todo: Todo = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(Some(1), todo.list_id);
+        }
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo {
+                list_id: Some(2),
+                ..CreateTodo::new("buy milk".to_string())
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::patch()
+            .uri("/todos/2")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/lists").to_request();
+        let lists: Vec<TodoList> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            vec![
+                TodoList {
+                    id: 1,
+                    name: "work".to_string(),
+                    open_count: 1,
+                    done_count: 1,
+                },
+                TodoList {
+                    id: 2,
+                    name: "home".to_string(),
+                    open_count: 1,
+                    done_count: 0,
+                },
+            ],
+            lists
+        );
+
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+        let req = test::TestRequest::get().uri("/lists/1/todos").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![2, 1], ids(todos));
+        let req = test::TestRequest::get()
+            .uri("/lists/1/todos?completed=false")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1], ids(todos));
+
+        // 別のリストへ移し、nullでリストから外す
+        let req = test::TestRequest::patch()
+            .uri("/todos/3")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"list_id":1}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(Some(1), todo.list_id);
+        let req = test::TestRequest::patch()
+            .uri("/todos/3")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"list_id":null}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(None, todo.list_id);
+
+        let req = test::TestRequest::post()
+            .uri("/lists/9/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("nowhere".to_string()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let req = test::TestRequest::get().uri("/lists/9/todos").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_delete_list_by_policy() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/lists")
+            .insert_header(ContentType::json())
+            .set_json(CreateList::new("work".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/lists/1/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("write report".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        // Todoが残っているので消せない
+        let req = test::TestRequest::delete().uri("/lists/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+
+        let req = test::TestRequest::delete()
+            .uri("/lists/1?policy=cascade")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let req = test::TestRequest::get().uri("/lists/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        let req = test::TestRequest::delete()
+            .uri("/lists/1?policy=orphan")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_nest_subtasks() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        // 1 ─┬─ 2 ── 4
+        //    └─ 3
+        for (text, parent_id) in [
+            ("release", None),
+            ("write notes", Some(1)),
+            ("tag version", Some(1)),
+            ("proofread", Some(2)),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::CREATED, resp.status());
+        }
+        let req = test::TestRequest::patch()
+            .uri("/todos/3")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let detail: TodoDetail = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(Progress { done: 1, total: 2 }, detail.progress);
+        // 完了していないので親はそのまま
+        assert!(!detail.todo.completed);
+
+        let req = test::TestRequest::get()
+            .uri("/todos/1/subtree")
+            .to_request();
+        let tree: TodoTree = test::call_and_read_body_json(&app, req).await;
+        let ids = |nodes: &[TodoTree]| nodes.iter().map(|n| n.todo.id).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3], ids(&tree.children));
+        assert_eq!(vec![4], ids(&tree.children[0].children));
+        assert_eq!(Progress { done: 0, total: 1 }, tree.children[0].progress);
+
+        // 子孫を親にはできない
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"parent_id":4}"#)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let resp: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!("validation_failed", resp.code);
+
+        let req = test::TestRequest::patch()
+            .uri("/todos/4")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"parent_id":null}"#)
+            .to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(None, todo.parent_id);
+
+        // 親を消すとサブタスクも消える
+        let req = test::TestRequest::delete().uri("/todos/1").to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![4], todos.iter().map(|t| t.id).collect::<Vec<_>>());
+
+        // 古いTodoを新しいTodoのサブタスクにしても、指定したTodoが根になる
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("publish".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::patch()
+            .uri("/todos/4")
+            .insert_header(ContentType::json())
+            .set_payload(r#"{"parent_id":5}"#)
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/todos/5/subtree")
+            .to_request();
+        let tree: TodoTree = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(5, tree.todo.id);
+        assert_eq!(vec![4], ids(&tree.children));
+    }
+
+    #[actix_web::test]
+    async fn should_auto_complete_parent() {
+        let repository =
+            web::Data::new(TodoRepositoryForMemory::new().with_auto_complete_parent(true));
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for (text, parent_id) in [
+            ("release", None),
+            ("write notes", Some(1)),
+            ("proofread", Some(2)),
+            ("tag version", Some(1)),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let complete = |id: i32| {
+            test::TestRequest::patch()
+                .uri(&format!("/todos/{id}"))
+                .insert_header(ContentType::json())
+                .set_json(UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                })
+                .to_request()
+        };
+        let find = |id: i32| {
+            test::TestRequest::get()
+                .uri(&format!("/todos/{id}"))
+                .to_request()
+        };
+
+        test::call_service(&app, complete(4)).await;
+        let todo: Todo = test::call_and_read_body_json(&app, find(1)).await;
+        assert!(!todo.completed);
+
+        // 3が完了すると2が完了し、続けて1も完了する
+        test::call_service(&app, complete(3)).await;
+        for id in [2, 1] {
+            let todo: Todo = test::call_and_read_body_json(&app, find(id)).await;
+            assert!(todo.completed);
+            assert!(todo.completed_at.is_some());
+        }
+    }
+
+    #[actix_web::test]
+    async fn should_auto_complete_recurring_parent() {
+        let repository =
+            web::Data::new(TodoRepositoryForMemory::new().with_auto_complete_parent(true));
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for (text, parent_id) in [("weekly review", None), ("clean inbox", Some(1))] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    parent_id,
+                    recurrence: Some("FREQ=WEEKLY".to_string()),
+                    ..CreateTodo::new(text.to_string())
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let req = test::TestRequest::patch()
+            .uri("/todos/2")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+
+        // サブタスクの次の回ができても親は完了になり、親の次の回もできる
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let todo: Todo = test::call_and_read_body_json(&app, req).await;
+        assert!(todo.completed);
+        let req = test::TestRequest::get()
+            .uri("/todos?completed=false")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            vec![("weekly review", None), ("clean inbox", Some(1))],
+            todos
+                .iter()
+                .map(|todo| (todo.text.as_str(), todo.parent_id))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[actix_web::test]
+    async fn should_block_completion_by_dependencies() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for text in ["deploy", "run tests", "review"] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo::new(text.to_string()))
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let add = |id: i32, blocker_id: i32| {
+            test::TestRequest::post()
+                .uri(&format!("/todos/{id}/dependencies"))
+                .insert_header(ContentType::json())
+                .set_json(AddDependency { blocker_id })
+                .to_request()
+        };
+        let complete = |id: i32| {
+            test::TestRequest::patch()
+                .uri(&format!("/todos/{id}"))
+                .insert_header(ContentType::json())
+                .set_json(UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                })
+                .to_request()
+        };
+
+        // 1は2に、2は3にブロックされる
+        let resp = test::call_service(&app, add(1, 2)).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        let blockers: Vec<Todo> = test::call_and_read_body_json(&app, add(2, 3)).await;
+        assert_eq!(vec![3], blockers.iter().map(|t| t.id).collect::<Vec<_>>());
+
+        // 循環する依存関係は追加できない
+        for (id, blocker_id) in [(3, 1), (2, 2)] {
+            let resp = test::call_service(&app, add(id, blocker_id)).await;
+            assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        }
+        let resp = test::call_service(&app, add(1, 99)).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let detail: TodoDetail = test::call_and_read_body_json(&app, req).await;
+        assert!(detail.blocked);
+
+        let resp = test::call_service(&app, complete(1)).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+        let resp: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!("conflict", resp.code);
+
+        // ブロックしているTodoが完了すれば完了にできる
+        test::call_service(&app, complete(3)).await;
+        test::call_service(&app, complete(2)).await;
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let detail: TodoDetail = test::call_and_read_body_json(&app, req).await;
+        assert!(!detail.blocked);
+        let resp = test::call_service(&app, complete(1)).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+
+        let req = test::TestRequest::delete()
+            .uri("/todos/1/dependencies/2")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+        let req = test::TestRequest::get()
+            .uri("/todos/1/dependencies")
+            .to_request();
+        let blockers: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert!(blockers.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn should_spawn_next_occurrence_of_recurring_todo() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        // 規則やタイムゾーンが不正なら作れない
+        for (recurrence, time_zone) in [
+            ("FREQ=YEARLY", "UTC"),
+            ("FREQ=WEEKLY;BYDAY=1MO", "UTC"),
+            ("FREQ=WEEKLY", "Mars/Olympus_Mons"),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(json!({
+                    "text": "take out the trash",
+                    "recurrence": recurrence,
+                    "time_zone": time_zone,
+                }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::BAD_REQUEST, resp.status(), "{}", recurrence);
+            let resp: ErrorResponse = test::read_body_json(resp).await;
+            assert_eq!("validation_failed", resp.code);
+        }
+
+        // ベルリンの毎週日曜09:00。冬時間に戻った後も現地の09:00のまま
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(json!({
+                "text": "take out the trash",
+                "due_at": "2026-10-18T07:00:00Z",
+                "recurrence": "FREQ=WEEKLY;COUNT=2",
+                "time_zone": "Europe/Berlin",
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+
+        let complete = |id: i32| {
+            test::TestRequest::patch()
+                .uri(&format!("/todos/{id}"))
+                .insert_header(ContentType::json())
+                .set_json(UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                })
+                .to_request()
+        };
+        test::call_service(&app, complete(1)).await;
+        let req = test::TestRequest::get()
+            .uri("/todos?completed=false")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, todos.len());
+        let next = &todos[0];
+        assert_eq!(2, next.id);
+        assert_eq!("take out the trash", next.text);
+        assert_eq!(
+            Some("2026-10-25T08:00:00Z"),
+            next.due_at
+                .map(|due_at| due_at.to_rfc3339_opts(SecondsFormat::Secs, true))
+                .as_deref()
+        );
+        assert_eq!(Some("FREQ=WEEKLY;COUNT=1"), next.recurrence.as_deref());
+        assert_eq!(Some("Europe/Berlin"), next.time_zone.as_deref());
+
+        // 完了済みのTodoを更新しても増えない。COUNTを使い切ったら次は作らない
+        test::call_service(&app, complete(1)).await;
+        test::call_service(&app, complete(2)).await;
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(2, todos.len());
+    }
+
+    #[actix_web::test]
+    async fn should_record_todo_history() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .insert_header((ACTOR, "alice"))
+            .set_json(CreateTodo::new("draft".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .insert_header((ACTOR, "bob"))
+            .set_json(UpdateTodo {
+                text: Some("publish".to_string()),
+                completed: Some(true),
+                ..Default::default()
+            })
+            .to_request();
+        test::call_service(&app, req).await;
+        // ヘッダーがなければ操作した人は残らない
+        let req = test::TestRequest::delete().uri("/todos/1").to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/todos/1/restore")
+            .insert_header((ACTOR, "alice"))
+            .to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get()
+            .uri("/todos/1/history")
+            .to_request();
+        let events: Vec<TodoEvent> = test::call_and_read_body_json(&app, req).await;
+        let summary = events
+            .iter()
+            .map(|event| (event.kind, event.actor.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (TodoEventKind::Created, Some("alice")),
+                (TodoEventKind::Updated, Some("bob")),
+                (TodoEventKind::Deleted, None),
+                (TodoEventKind::Restored, Some("alice")),
+            ],
+            summary
+        );
+        let updated = &events[1];
+        assert_eq!(Some("draft"), updated.old_text.as_deref());
+        assert_eq!(Some("publish"), updated.new_text.as_deref());
+        assert_eq!(Some(false), updated.old_completed);
+        assert_eq!(Some(true), updated.new_completed);
+        assert_eq!(None, events[2].new_text);
+
+        // 完全に削除しても履歴は読める
+        let req = test::TestRequest::delete().uri("/todos/1").to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::delete().uri("/trash/1").to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/todos/1/history")
+            .to_request();
+        let events: Vec<TodoEvent> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            Some(TodoEventKind::Purged),
+            events.last().map(|event| event.kind)
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/todos/2/history")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    fn etag(resp: &ServiceResponse) -> String {
+        let etag = resp.headers().get(header::ETAG).unwrap();
+        etag.to_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn should_check_version_by_if_match() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("write draft".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::get().uri("/todos/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(etag(&resp).starts_with("\"1-"));
+
+        let update = |if_match: &str| {
+            test::TestRequest::patch()
+                .uri("/todos/1")
+                .insert_header(ContentType::json())
+                .insert_header((header::IF_MATCH, if_match))
+                .set_json(UpdateTodo {
+                    text: Some("write article".to_string()),
+                    ..Default::default()
+                })
+                .to_request()
+        };
+        let resp = test::call_service(&app, update("\"1\"")).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        assert!(etag(&resp).starts_with("\"2-"));
+
+        // 古いversionや弱いETagでは更新も削除もできない
+        for if_match in ["\"1\"", "W/\"2\"", "draft"] {
+            let resp = test::call_service(&app, update(if_match)).await;
+            assert_eq!(
+                StatusCode::PRECONDITION_FAILED,
+                resp.status(),
+                "{}",
+                if_match
+            );
+            let resp: ErrorResponse = test::read_body_json(resp).await;
+            assert_eq!("precondition_failed", resp.code);
+        }
+        let delete = |if_match: &str| {
+            test::TestRequest::delete()
+                .uri("/todos/1")
+                .insert_header((header::IF_MATCH, if_match))
+                .to_request()
+        };
+        let resp = test::call_service(&app, delete("\"1\"")).await;
+        assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+
+        // `*`は存在していればどのversionでも一致する
+        let resp = test::call_service(&app, update("*")).await;
+        assert!(etag(&resp).starts_with("\"3-"));
+        let resp = test::call_service(&app, delete("\"3\"")).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+        let resp = test::call_service(&app, delete("*")).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_answer_not_modified() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let create = |text: &str, parent_id: Option<i32>| {
+            test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .to_request()
+        };
+        test::call_service(&app, create("plan trip", None)).await;
+        let get = |uri: &str, headers: Vec<(header::HeaderName, String)>| {
+            let mut req = test::TestRequest::get().uri(uri);
+            for header in headers {
+                req = req.insert_header(header);
+            }
+            req.to_request()
+        };
+
+        // 同じ内容なら同じETagになり、一致すれば304を返す。弱い比較なのでW/付きでも一致する
+        let resp = test::call_service(&app, get("/todos", vec![])).await;
+        let list_etag = etag(&resp);
+        assert!(resp.headers().get(header::LAST_MODIFIED).is_none());
+        let resp = test::call_service(&app, get("/todos", vec![])).await;
+        assert_eq!(list_etag, etag(&resp));
+        for if_none_match in [list_etag.clone(), format!("W/{list_etag}"), "*".to_string()] {
+            let resp = test::call_service(
+                &app,
+                get("/todos", vec![(header::IF_NONE_MATCH, if_none_match)]),
+            )
+            .await;
+            assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+            assert_eq!(list_etag, etag(&resp));
+            assert!(test::read_body(resp).await.is_empty());
+        }
+
+        // Todoの詳細はLast-Modifiedも返す
+        let resp = test::call_service(&app, get("/todos/1", vec![])).await;
+        let todo_etag = etag(&resp);
+        let last_modified = resp.headers().get(header::LAST_MODIFIED).unwrap();
+        let last_modified = last_modified.to_str().unwrap().to_string();
+        let resp = test::call_service(
+            &app,
+            get(
+                "/todos/1",
+                vec![(header::IF_MODIFIED_SINCE, last_modified.clone())],
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+        let an_hour_ago = header::HttpDate::from(SystemTime::from(Utc::now() - Duration::hours(1)));
+        let resp = test::call_service(
+            &app,
+            get(
+                "/todos/1",
+                vec![(header::IF_MODIFIED_SINCE, an_hour_ago.to_string())],
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        // サブタスクを足すとTodo自体は変わらなくても進捗が変わるので、ETagも変わる。
+        // If-None-MatchがあればIf-Modified-Sinceは見ない
+        test::call_service(&app, create("book hotel", Some(1))).await;
+        let resp = test::call_service(
+            &app,
+            get(
+                "/todos/1",
+                vec![
+                    (header::IF_NONE_MATCH, todo_etag.clone()),
+                    (header::IF_MODIFIED_SINCE, last_modified),
+                ],
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_ne!(todo_etag, etag(&resp));
+        assert!(etag(&resp).starts_with("\"1-"));
+        let resp = test::call_service(
+            &app,
+            get("/todos", vec![(header::IF_NONE_MATCH, list_etag.clone())]),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_ne!(list_etag, etag(&resp));
+
+        // PATCHで返したETagはGETと同じなので、そのままIf-None-Matchに使える
+        let req = test::TestRequest::patch()
+            .uri("/todos/1")
+            .insert_header(ContentType::json())
+            .set_json(UpdateTodo {
+                text: Some("plan holiday".to_string()),
+                ..Default::default()
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let patched_etag = etag(&resp);
+        assert!(patched_etag.starts_with("\"2-"));
+        let resp = test::call_service(&app, get("/todos/1", vec![])).await;
+        assert_eq!(patched_etag, etag(&resp));
+        let resp = test::call_service(
+            &app,
+            get("/todos/1", vec![(header::IF_NONE_MATCH, patched_etag)]),
+        )
+        .await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn should_run_bulk_operations() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/todos")
+            .insert_header(ContentType::json())
+            .set_json(CreateTodo::new("write draft".to_string()))
+            .to_request();
+        test::call_service(&app, req).await;
+        let bulk = |uri: &str, operations: Vec<BulkOperation>| {
+            test::TestRequest::post()
+                .uri(uri)
+                .insert_header(ContentType::json())
+                .set_json(operations)
+                .to_request()
+        };
+        let operations = |text: &str, version: Option<i32>| {
+            vec![
+                BulkOperation::Create {
+                    todo: CreateTodo::new(text.to_string()),
+                },
+                BulkOperation::Update {
+                    id: 1,
+                    version,
+                    todo: UpdateTodo {
+                        text: Some("write article".to_string()),
+                        ..Default::default()
+                    },
+                },
+                BulkOperation::Delete {
+                    id: 99,
+                    version: None,
+                },
+            ]
+        };
+        let statuses =
+            |items: &[BulkItem]| items.iter().map(|item| item.status).collect::<Vec<_>>();
+        let todos = |app| async move {
+            let req = test::TestRequest::get().uri("/todos").to_request();
+            let todos: Vec<Todo> = test::call_and_read_body_json(app, req).await;
+            todos.into_iter().map(|todo| todo.text).collect::<Vec<_>>()
+        };
+
+        // 1件でも失敗すれば全て取り消す
+        let resp = test::call_service(&app, bulk("/todos/bulk", operations("publish", None))).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let items: Vec<BulkItem> = test::read_body_json(resp).await;
+        assert_eq!(vec![424, 424, 404], statuses(&items));
+        assert_eq!("not_found", items[2].error.as_ref().unwrap().code);
+        assert_eq!(vec!["write draft"], todos(&app).await);
+
+        // 不正な操作があれば何も実行しない
+        let resp = test::call_service(&app, bulk("/todos/bulk", operations("", None))).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let items: Vec<BulkItem> = test::read_body_json(resp).await;
+        assert_eq!(vec![400, 424, 424], statuses(&items));
+        assert_eq!("validation_failed", items[0].error.as_ref().unwrap().code);
+
+        // atomic=falseなら失敗した操作だけを飛ばす
+        let mut partial = operations("", Some(5));
+        partial.extend(operations("publish", Some(1)));
+        let resp = test::call_service(&app, bulk("/todos/bulk?atomic=false", partial)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let items: Vec<BulkItem> = test::read_body_json(resp).await;
+        assert_eq!(vec![400, 412, 404, 201, 200, 404], statuses(&items));
+        assert_eq!("publish", items[3].todo.as_ref().unwrap().text);
+        assert_eq!(2, items[4].todo.as_ref().unwrap().version);
+        assert_eq!(vec!["publish", "write article"], todos(&app).await);
+
+        // 全て成功すれば200で、削除はTodoを返さない
+        let resp = test::call_service(
+            &app,
+            bulk(
+                "/todos/bulk",
+                vec![BulkOperation::Delete {
+                    id: 1,
+                    version: Some(2),
+                }],
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::OK, resp.status());
+        let items: Vec<BulkItem> = test::read_body_json(resp).await;
+        assert_eq!(
+            vec![BulkItem {
+                status: 204,
+                todo: None,
+                error: None
+            }],
+            items
+        );
+
+        let resp = test::call_service(
+            &app,
+            bulk(
+                "/todos/bulk",
+                vec![
+                    BulkOperation::Delete {
+                        id: 2,
+                        version: None
+                    };
+                    BULK_LIMIT + 1
+                ],
+            ),
+        )
+        .await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        assert_eq!(vec!["publish"], todos(&app).await);
+    }
+
+    #[actix_web::test]
+    async fn should_update_and_delete_matching_todos() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository)
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        for (text, priority, parent_id) in [
+            ("book hotel", Priority::High, None),
+            ("book flight", Priority::High, None),
+            ("pack", Priority::None, None),
+            ("pack camera", Priority::None, Some(3)),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .set_json(CreateTodo {
+                    priority,
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .to_request();
+            test::call_service(&app, req).await;
+        }
+        let complete = |uri: &str| {
+            test::TestRequest::patch()
+                .uri(uri)
+                .insert_header(ContentType::json())
+                .set_json(UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                })
+                .to_request()
+        };
+        let delete = |uri: &str| test::TestRequest::delete().uri(uri).to_request();
+        let total = |app| async move {
+            let req = test::TestRequest::get().uri("/todos").to_request();
+            let resp: ServiceResponse = test::call_service(app, req).await;
+            resp.headers()
+                .get(TOTAL_COUNT)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        // confirm=trueがなければ何もしない
+        for req in [
+            complete("/todos?priority=high"),
+            complete("/todos?priority=high&confirm=false"),
+            delete("/todos?completed=true"),
+        ] {
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+            let resp: ErrorResponse = test::read_body_json(resp).await;
+            assert_eq!("validation_failed", resp.code);
+            assert!(resp.details.unwrap().get("confirm").is_some());
+        }
+
+        let resp: Affected =
+            test::call_and_read_body_json(&app, complete("/todos?priority=high&confirm=true"))
+                .await;
+        assert_eq!(Affected { affected: 2 }, resp);
+        let req = test::TestRequest::get()
+            .uri("/todos?completed=true")
+            .to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            vec![2, 1],
+            todos.iter().map(|todo| todo.id).collect::<Vec<_>>()
+        );
+
+        // サブタスクは親と一緒に移るので、1件として数える
+        let resp: Affected =
+            test::call_and_read_body_json(&app, delete("/todos?completed=false&confirm=true"))
+                .await;
+        assert_eq!(Affected { affected: 2 }, resp);
+        assert_eq!("2", total(&app).await);
+        let resp: Affected =
+            test::call_and_read_body_json(&app, delete("/todos?q=nothing&confirm=true")).await;
+        assert_eq!(Affected { affected: 0 }, resp);
+        assert_eq!("2", total(&app).await);
+    }
+
+    #[actix_web::test]
+    async fn should_replay_create_by_idempotency_key() {
+        let repository = web::Data::new(TodoRepositoryForMemory::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(repository.clone())
+                .configure(config::<TodoRepositoryForMemory>),
+        )
+        .await;
+
+        let create = |key: &str, payload: CreateTodo| {
+            test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .insert_header((IDEMPOTENCY_KEY, key))
+                .set_json(payload)
+                .to_request()
+        };
+        let buy_milk = || CreateTodo::new("buy milk".to_string());
+
+        // リトライしても同じTodoを返し、2回は作らない
+        let resp = test::call_service(&app, create("retry-1", buy_milk())).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        assert!(resp.headers().get(IDEMPOTENT_REPLAYED).is_none());
+        let created: Todo = test::read_body_json(resp).await;
+        let resp = test::call_service(&app, create("retry-1", buy_milk())).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        assert_eq!("true", resp.headers().get(IDEMPOTENT_REPLAYED).unwrap());
+        let replayed: Todo = test::read_body_json(resp).await;
+        assert_eq!(created, replayed);
+        let req = test::TestRequest::get().uri("/todos").to_request();
+        let todos: Vec<Todo> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, todos.len());
+
+        // 同じキーで違うボディなら422
+        let resp = test::call_service(
+            &app,
+            create("retry-1", CreateTodo::new("buy eggs".to_string())),
+        )
+        .await;
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, resp.status());
+        let resp: ErrorResponse = test::read_body_json(resp).await;
+        assert_eq!("idempotency_key_reused", resp.code);
+
+        // 失敗したリクエストはキーを残さないので、直してやり直せる
+        let in_missing_list = CreateTodo {
+            list_id: Some(99),
+            ..CreateTodo::new("buy eggs".to_string())
+        };
+        let resp = test::call_service(&app, create("retry-2", in_missing_list)).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let resp = test::call_service(
+            &app,
+            create("retry-2", CreateTodo::new("buy eggs".to_string())),
+        )
+        .await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+
+        // 処理中のキーは409
+        let req = test::TestRequest::post().uri("/todos").to_http_request();
+        repository
+            .reserve_key("retry-3", &request_hash(&req, &buy_milk()))
+            .await
+            .unwrap();
+        let resp = test::call_service(&app, create("retry-3", buy_milk())).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+
+        let resp = test::call_service(&app, create("", buy_milk())).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+    }
+}
@@ -0,0 +1,686 @@
+use chrono::{
+    DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, Offset, TimeZone,
+    Utc, Weekday,
+};
+use chrono_tz::Tz;
+use std::{fmt, str::FromStr};
+use thiserror::Error;
+
+// 次の発生日を探す時に進める回数の上限。条件に合う日が見つからない規則で無限ループしないようにする。
+const MAX_STEPS: i64 = 1000;
+// INTERVALの上限。大きすぎると日付の計算が扱える範囲を超える。
+const MAX_INTERVAL: u32 = 1000;
+
+// RFC 5545のRRULEのうち、FREQ(DAILY/WEEKLY/MONTHLY)、INTERVAL、BYDAY、UNTIL、COUNTに対応する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub by_day: Vec<ByDay>,
+    pub until: Option<Until>,
+    pub count: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+// BYDAYの1要素。ordinalはMONTHLYの時だけ使え、2TUなら第2火曜、-1FRなら最終金曜になる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByDay {
+    pub ordinal: Option<i8>,
+    pub weekday: Weekday,
+}
+
+// UNTILは日付だけ(その日を含む)か、UTCの日時で指定する。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Until {
+    Date(NaiveDate),
+    DateTime(DateTime<Utc>),
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RRuleError {
+    #[error("FREQ is required")]
+    MissingFrequency,
+    #[error("unsupported FREQ [{0}]")]
+    UnsupportedFrequency(String),
+    #[error("unsupported rule part [{0}]")]
+    UnsupportedPart(String),
+    #[error("{0} is specified more than once")]
+    DuplicatePart(String),
+    #[error("invalid {0} [{1}]")]
+    InvalidValue(&'static str, String),
+    #[error("UNTIL and COUNT can not be used together")]
+    UntilWithCount,
+    #[error("BYDAY with ordinal can only be used with FREQ=MONTHLY")]
+    OrdinalByDay,
+}
+
+impl FromStr for RRule {
+    type Err = RRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let s = match s.get(..6) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("RRULE:") => &s[6..],
+            _ => s,
+        };
+
+        let mut frequency = None;
+        let mut interval = None;
+        let mut by_day = None;
+        let mut until = None;
+        let mut count = None;
+        for part in s.split(';') {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| RRuleError::InvalidValue("rule part", part.to_string()))?;
+            let key = key.trim().to_ascii_uppercase();
+            let value = value.trim();
+            let duplicated = match key.as_str() {
+                "FREQ" => frequency.replace(parse_frequency(value)?).is_some(),
+                "INTERVAL" => interval
+                    .replace(parse_positive("INTERVAL", value, MAX_INTERVAL)?)
+                    .is_some(),
+                "BYDAY" => by_day.replace(parse_by_day_list(value)?).is_some(),
+                "UNTIL" => until.replace(parse_until(value)?).is_some(),
+                "COUNT" => count
+                    .replace(parse_positive("COUNT", value, u32::MAX)?)
+                    .is_some(),
+                _ => return Err(RRuleError::UnsupportedPart(key)),
+            };
+            if duplicated {
+                return Err(RRuleError::DuplicatePart(key));
+            }
+        }
+
+        let frequency = frequency.ok_or(RRuleError::MissingFrequency)?;
+        let by_day: Vec<ByDay> = by_day.unwrap_or_default();
+        if until.is_some() && count.is_some() {
+            return Err(RRuleError::UntilWithCount);
+        }
+        if frequency != Frequency::Monthly && by_day.iter().any(|day| day.ordinal.is_some()) {
+            return Err(RRuleError::OrdinalByDay);
+        }
+        Ok(Self {
+            frequency,
+            interval: interval.unwrap_or(1),
+            by_day,
+            until,
+            count,
+        })
+    }
+}
+
+fn parse_frequency(value: &str) -> Result<Frequency, RRuleError> {
+    match value.to_ascii_uppercase().as_str() {
+        "DAILY" => Ok(Frequency::Daily),
+        "WEEKLY" => Ok(Frequency::Weekly),
+        "MONTHLY" => Ok(Frequency::Monthly),
+        _ => Err(RRuleError::UnsupportedFrequency(value.to_string())),
+    }
+}
+
+fn parse_positive(name: &'static str, value: &str, max: u32) -> Result<u32, RRuleError> {
+    match value.parse() {
+        Ok(number) if (1..=max).contains(&number) => Ok(number),
+        _ => Err(RRuleError::InvalidValue(name, value.to_string())),
+    }
+}
+
+fn parse_by_day_list(value: &str) -> Result<Vec<ByDay>, RRuleError> {
+    value.split(',').map(parse_by_day).collect()
+}
+
+fn parse_by_day(value: &str) -> Result<ByDay, RRuleError> {
+    let invalid = || RRuleError::InvalidValue("BYDAY", value.to_string());
+    let value = value.trim();
+    let split = value.len().checked_sub(2).ok_or_else(invalid)?;
+    let (ordinal, weekday) = (value.get(..split), value.get(split..));
+    let weekday = match weekday.ok_or_else(invalid)?.to_ascii_uppercase().as_str() {
+        "MO" => Weekday::Mon,
+        "TU" => Weekday::Tue,
+        "WE" => Weekday::Wed,
+        "TH" => Weekday::Thu,
+        "FR" => Weekday::Fri,
+        "SA" => Weekday::Sat,
+        "SU" => Weekday::Sun,
+        _ => return Err(invalid()),
+    };
+    let ordinal = match ordinal.ok_or_else(invalid)? {
+        "" => None,
+        ordinal => match ordinal.parse::<i8>() {
+            Ok(n) if n != 0 && (-5..=5).contains(&n) => Some(n),
+            _ => return Err(invalid()),
+        },
+    };
+    Ok(ByDay { ordinal, weekday })
+}
+
+fn parse_until(value: &str) -> Result<Until, RRuleError> {
+    let invalid = || RRuleError::InvalidValue("UNTIL", value.to_string());
+    if value.len() == 8 {
+        return NaiveDate::parse_from_str(value, "%Y%m%d")
+            .map(Until::Date)
+            .map_err(|_| invalid());
+    }
+    // 日時の場合はUTC(末尾Z)だけを受け付ける
+    let value = value.strip_suffix('Z').ok_or_else(invalid)?;
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S")
+        .map(|datetime| Until::DateTime(Utc.from_utc_datetime(&datetime)))
+        .map_err(|_| invalid())
+}
+
+impl fmt::Display for RRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let frequency = match self.frequency {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+        };
+        write!(f, "FREQ={}", frequency)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<String> = self.by_day.iter().map(ToString::to_string).collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        match self.until {
+            Some(Until::Date(date)) => write!(f, ";UNTIL={}", date.format("%Y%m%d"))?,
+            Some(Until::DateTime(datetime)) => {
+                write!(f, ";UNTIL={}", datetime.format("%Y%m%dT%H%M%SZ"))?
+            }
+            None => {}
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ByDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ordinal) = self.ordinal {
+            write!(f, "{}", ordinal)?;
+        }
+        let weekday = match self.weekday {
+            Weekday::Mon => "MO",
+            Weekday::Tue => "TU",
+            Weekday::Wed => "WE",
+            Weekday::Thu => "TH",
+            Weekday::Fri => "FR",
+            Weekday::Sat => "SA",
+            Weekday::Sun => "SU",
+        };
+        f.write_str(weekday)
+    }
+}
+
+impl RRule {
+    // dueの次の発生日時と、その発生に使う規則(COUNTを1つ減らしたもの)を返す。
+    // 日付の計算はtzの現地時刻で行うので、夏時間をまたいでも現地の時刻は変わらない。
+    // UNTILを過ぎたかCOUNTを使い切った場合はNone。
+    pub fn next(&self, due: DateTime<Utc>, tz: Tz) -> Option<(DateTime<Utc>, RRule)> {
+        if self.count.is_some_and(|count| count <= 1) {
+            return None;
+        }
+        let local = due.with_timezone(&tz).naive_local();
+        let date = self.next_date(local.date())?;
+        let next = resolve_local(tz, date.and_time(local.time()));
+        match self.until {
+            Some(Until::Date(until)) if date > until => return None,
+            Some(Until::DateTime(until)) if next > until => return None,
+            _ => {}
+        }
+        let rule = RRule {
+            count: self.count.map(|count| count - 1),
+            ..self.clone()
+        };
+        Some((next, rule))
+    }
+
+    // 扱える日付の範囲を超える場合はNone。
+    fn next_date(&self, date: NaiveDate) -> Option<NaiveDate> {
+        let interval = i64::from(self.interval);
+        match self.frequency {
+            Frequency::Daily => (1..=MAX_STEPS)
+                .map_while(|step| date.checked_add_signed(Duration::days(step * interval)))
+                .find(|day| self.matches_weekday(*day)),
+            Frequency::Weekly if self.by_day.is_empty() => {
+                date.checked_add_signed(Duration::weeks(interval))
+            }
+            Frequency::Weekly => {
+                // 週は月曜始まり(WKST=MO)。同じ週の残りの曜日がなければinterval週後の週から探す。
+                let monday = date.checked_sub_signed(Duration::days(i64::from(
+                    date.weekday().num_days_from_monday(),
+                )))?;
+                let week = |monday: NaiveDate| {
+                    (0..7)
+                        .filter_map(move |day| monday.checked_add_signed(Duration::days(day)))
+                        .filter(|day| self.matches_weekday(*day))
+                };
+                week(monday)
+                    .find(|day| *day > date)
+                    .or_else(|| week(monday.checked_add_signed(Duration::weeks(interval))?).next())
+            }
+            Frequency::Monthly => {
+                let first = date.with_day(1)?;
+                (0..=MAX_STEPS)
+                    .map_while(|step| {
+                        let months = u32::try_from(step * interval).ok()?;
+                        first.checked_add_months(Months::new(months))
+                    })
+                    .find_map(|month| {
+                        self.month_dates(month.year(), month.month(), date.day())
+                            .into_iter()
+                            .find(|day| *day > date)
+                    })
+            }
+        }
+    }
+
+    fn matches_weekday(&self, date: NaiveDate) -> bool {
+        self.by_day.is_empty() || self.by_day.iter().any(|day| day.weekday == date.weekday())
+    }
+
+    // 指定した月の中で規則に合う日付を昇順で返す。
+    // BYDAYがなければ元の日付と同じ日で、その日がない月(31日など)は飛ばす。
+    fn month_dates(&self, year: i32, month: u32, day: u32) -> Vec<NaiveDate> {
+        if self.by_day.is_empty() {
+            return NaiveDate::from_ymd_opt(year, month, day)
+                .into_iter()
+                .collect();
+        }
+        let days: Vec<NaiveDate> = (1..=31)
+            .filter_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+            .collect();
+        let mut dates: Vec<NaiveDate> = self
+            .by_day
+            .iter()
+            .flat_map(|by_day| {
+                let matched: Vec<NaiveDate> = days
+                    .iter()
+                    .copied()
+                    .filter(|day| day.weekday() == by_day.weekday)
+                    .collect();
+                match by_day.ordinal {
+                    None => matched,
+                    Some(n) if n > 0 => matched.get(n as usize - 1).copied().into_iter().collect(),
+                    Some(n) => matched
+                        .len()
+                        .checked_sub(n.unsigned_abs() as usize)
+                        .and_then(|index| matched.get(index).copied())
+                        .into_iter()
+                        .collect(),
+                }
+            })
+            .collect();
+        dates.sort();
+        dates.dedup();
+        dates
+    }
+}
+
+// 現地時刻をUTCに変換する。RFC 5545と同じく、夏時間の終わりで2回ある時刻は早い方、
+// 夏時間の始まりで存在しない時刻は切り替え前のオフセットで解釈する(結果として後ろにずれる)。
+fn resolve_local(tz: Tz, local: NaiveDateTime) -> DateTime<Utc> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(datetime) | LocalResult::Ambiguous(datetime, _) => {
+            datetime.with_timezone(&Utc)
+        }
+        LocalResult::None => {
+            let offset = tz
+                .offset_from_utc_datetime(&(local - Duration::days(1)))
+                .fix();
+            Utc.from_utc_datetime(&(local - Duration::seconds(offset.local_minus_utc().into())))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn next(rule: &str, due: &str, tz: Tz) -> Option<(DateTime<Utc>, String)> {
+        let rule: RRule = rule.parse().unwrap();
+        rule.next(utc(due), tz)
+            .map(|(next, rule)| (next, rule.to_string()))
+    }
+
+    fn occurrences(rule: &str, due: &str, tz: Tz, n: usize) -> Vec<DateTime<Utc>> {
+        let mut rule: RRule = rule.parse().unwrap();
+        let mut due = utc(due);
+        let mut dates = vec![];
+        while dates.len() < n {
+            match rule.next(due, tz) {
+                Some((next, next_rule)) => {
+                    dates.push(next);
+                    due = next;
+                    rule = next_rule;
+                }
+                None => break,
+            }
+        }
+        dates
+    }
+
+    #[test]
+    fn should_parse_rules() {
+        let rule: RRule = "RRULE:FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,fr;COUNT=3"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule,
+            RRule {
+                frequency: Frequency::Weekly,
+                interval: 2,
+                by_day: vec![
+                    ByDay {
+                        ordinal: None,
+                        weekday: Weekday::Mon
+                    },
+                    ByDay {
+                        ordinal: None,
+                        weekday: Weekday::Fri
+                    },
+                ],
+                until: None,
+                count: Some(3),
+            }
+        );
+        assert_eq!(
+            rule.to_string(),
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,FR;COUNT=3"
+        );
+
+        let rule: RRule = "freq=monthly;byday=-1FR,2TU;until=20261231T000000Z"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule.to_string(),
+            "FREQ=MONTHLY;BYDAY=-1FR,2TU;UNTIL=20261231T000000Z"
+        );
+        let rule: RRule = "FREQ=DAILY;UNTIL=20261231".parse().unwrap();
+        assert_eq!(
+            rule.until,
+            Some(Until::Date(NaiveDate::from_ymd_opt(2026, 12, 31).unwrap()))
+        );
+    }
+
+    #[test]
+    fn should_reject_invalid_rules() {
+        let cases = [
+            ("", RRuleError::InvalidValue("rule part", "".to_string())),
+            ("INTERVAL=2", RRuleError::MissingFrequency),
+            (
+                "FREQ=YEARLY",
+                RRuleError::UnsupportedFrequency("YEARLY".to_string()),
+            ),
+            (
+                "FREQ=DAILY;WKST=MO",
+                RRuleError::UnsupportedPart("WKST".to_string()),
+            ),
+            (
+                "FREQ=DAILY;FREQ=WEEKLY",
+                RRuleError::DuplicatePart("FREQ".to_string()),
+            ),
+            (
+                "FREQ=DAILY;INTERVAL=0",
+                RRuleError::InvalidValue("INTERVAL", "0".to_string()),
+            ),
+            (
+                "FREQ=DAILY;INTERVAL=1000000000",
+                RRuleError::InvalidValue("INTERVAL", "1000000000".to_string()),
+            ),
+            (
+                "FREQ=DAILY;COUNT=-1",
+                RRuleError::InvalidValue("COUNT", "-1".to_string()),
+            ),
+            (
+                "FREQ=WEEKLY;BYDAY=XX",
+                RRuleError::InvalidValue("BYDAY", "XX".to_string()),
+            ),
+            (
+                "FREQ=MONTHLY;BYDAY=0MO",
+                RRuleError::InvalidValue("BYDAY", "0MO".to_string()),
+            ),
+            (
+                "FREQ=MONTHLY;BYDAY=6MO",
+                RRuleError::InvalidValue("BYDAY", "6MO".to_string()),
+            ),
+            ("FREQ=WEEKLY;BYDAY=1MO", RRuleError::OrdinalByDay),
+            (
+                "FREQ=DAILY;UNTIL=2026-12-31",
+                RRuleError::InvalidValue("UNTIL", "2026-12-31".to_string()),
+            ),
+            (
+                "FREQ=DAILY;UNTIL=20261231T000000",
+                RRuleError::InvalidValue("UNTIL", "20261231T000000".to_string()),
+            ),
+            (
+                "FREQ=DAILY;UNTIL=20261231;COUNT=2",
+                RRuleError::UntilWithCount,
+            ),
+        ];
+        for (rule, expected) in cases {
+            assert_eq!(rule.parse::<RRule>(), Err(expected), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn should_repeat_daily_and_weekly() {
+        let tz = chrono_tz::UTC;
+        assert_eq!(
+            occurrences("FREQ=DAILY;INTERVAL=3", "2026-10-18T09:00:00Z", tz, 2),
+            vec![utc("2026-10-21T09:00:00Z"), utc("2026-10-24T09:00:00Z")]
+        );
+        // 2026-10-16は金曜。平日だけなので週末を飛ばす
+        assert_eq!(
+            occurrences(
+                "FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR",
+                "2026-10-16T09:00:00Z",
+                tz,
+                2
+            ),
+            vec![utc("2026-10-19T09:00:00Z"), utc("2026-10-20T09:00:00Z")]
+        );
+        assert_eq!(
+            occurrences("FREQ=WEEKLY", "2026-10-18T09:00:00Z", tz, 1),
+            vec![utc("2026-10-25T09:00:00Z")]
+        );
+        // 2026-10-14(水)から隔週の月・金
+        assert_eq!(
+            occurrences(
+                "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,FR",
+                "2026-10-14T09:00:00Z",
+                tz,
+                3
+            ),
+            vec![
+                utc("2026-10-16T09:00:00Z"),
+                utc("2026-10-26T09:00:00Z"),
+                utc("2026-10-30T09:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn should_repeat_monthly() {
+        let tz = chrono_tz::UTC;
+        // 31日がない月は飛ばす
+        assert_eq!(
+            occurrences("FREQ=MONTHLY", "2026-01-31T09:00:00Z", tz, 3),
+            vec![
+                utc("2026-03-31T09:00:00Z"),
+                utc("2026-05-31T09:00:00Z"),
+                utc("2026-07-31T09:00:00Z"),
+            ]
+        );
+        assert_eq!(
+            occurrences("FREQ=MONTHLY;INTERVAL=5", "2026-10-18T09:00:00Z", tz, 1),
+            vec![utc("2027-03-18T09:00:00Z")]
+        );
+        // 最終金曜
+        assert_eq!(
+            occurrences("FREQ=MONTHLY;BYDAY=-1FR", "2026-10-30T09:00:00Z", tz, 2),
+            vec![utc("2026-11-27T09:00:00Z"), utc("2026-12-25T09:00:00Z")]
+        );
+        // 第1月曜と第3水曜
+        assert_eq!(
+            occurrences("FREQ=MONTHLY;BYDAY=1MO,3WE", "2026-10-18T09:00:00Z", tz, 3),
+            vec![
+                utc("2026-10-21T09:00:00Z"),
+                utc("2026-11-02T09:00:00Z"),
+                utc("2026-11-18T09:00:00Z"),
+            ]
+        );
+        // 第5木曜がない月は飛ばす
+        assert_eq!(
+            occurrences("FREQ=MONTHLY;BYDAY=5TH", "2026-10-29T09:00:00Z", tz, 1),
+            vec![utc("2026-12-31T09:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn should_stop_at_count_and_until() {
+        let tz = chrono_tz::UTC;
+        assert_eq!(
+            next("FREQ=DAILY;COUNT=3", "2026-10-18T09:00:00Z", tz),
+            Some((
+                utc("2026-10-19T09:00:00Z"),
+                "FREQ=DAILY;COUNT=2".to_string()
+            ))
+        );
+        assert_eq!(next("FREQ=DAILY;COUNT=1", "2026-10-18T09:00:00Z", tz), None);
+        assert_eq!(
+            occurrences("FREQ=DAILY;COUNT=3", "2026-10-18T09:00:00Z", tz, 10).len(),
+            2
+        );
+
+        // 日付のUNTILはその日を含む
+        assert_eq!(
+            occurrences("FREQ=DAILY;UNTIL=20261020", "2026-10-18T23:00:00Z", tz, 10),
+            vec![utc("2026-10-19T23:00:00Z"), utc("2026-10-20T23:00:00Z")]
+        );
+        assert_eq!(
+            occurrences(
+                "FREQ=DAILY;UNTIL=20261020T090000Z",
+                "2026-10-18T09:00:00Z",
+                tz,
+                10
+            ),
+            vec![utc("2026-10-19T09:00:00Z"), utc("2026-10-20T09:00:00Z")]
+        );
+        assert_eq!(
+            next(
+                "FREQ=DAILY;UNTIL=20261020T085959Z",
+                "2026-10-19T09:00:00Z",
+                tz
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn should_stop_at_end_of_supported_dates() {
+        let tz = chrono_tz::UTC;
+        let due = Utc.from_utc_datetime(&NaiveDate::MAX.and_hms_opt(9, 0, 0).unwrap())
+            - Duration::days(400);
+        for rule in [
+            "FREQ=DAILY;INTERVAL=1000",
+            "FREQ=WEEKLY;INTERVAL=1000",
+            "FREQ=WEEKLY;INTERVAL=1000;BYDAY=MO",
+            "FREQ=MONTHLY;INTERVAL=1000",
+        ] {
+            let rule: RRule = rule.parse().unwrap();
+            assert_eq!(None, rule.next(due, tz), "{}", rule);
+        }
+        assert!(next("FREQ=DAILY;INTERVAL=1000", "2026-10-18T09:00:00Z", tz).is_some());
+    }
+
+    #[test]
+    fn should_keep_local_time_over_dst_in_europe() {
+        let tz = chrono_tz::Europe::Berlin;
+        // 2026-03-29に夏時間(+02:00)が始まる
+        assert_eq!(
+            occurrences("FREQ=DAILY", "2026-03-28T08:00:00Z", tz, 2),
+            vec![utc("2026-03-29T07:00:00Z"), utc("2026-03-30T07:00:00Z")]
+        );
+        // 2026-10-25に冬時間(+01:00)に戻る
+        assert_eq!(
+            occurrences("FREQ=WEEKLY", "2026-10-18T07:00:00Z", tz, 2),
+            vec![utc("2026-10-25T08:00:00Z"), utc("2026-11-01T08:00:00Z")]
+        );
+        // 日付の境界も現地時刻で判定する(UTCでは前日の23時)
+        assert_eq!(
+            occurrences("FREQ=MONTHLY", "2026-03-31T22:30:00Z", tz, 1),
+            vec![utc("2026-04-30T22:30:00Z")]
+        );
+    }
+
+    #[test]
+    fn should_keep_local_time_over_dst_in_america() {
+        let tz = chrono_tz::America::New_York;
+        // 2026-03-08に夏時間(-04:00)が始まる
+        assert_eq!(
+            occurrences("FREQ=DAILY", "2026-03-07T14:00:00Z", tz, 2),
+            vec![utc("2026-03-08T13:00:00Z"), utc("2026-03-09T13:00:00Z")]
+        );
+        // 2026-11-01に標準時(-05:00)に戻る。月曜の09:00のまま
+        assert_eq!(
+            occurrences("FREQ=WEEKLY;BYDAY=MO", "2026-10-26T13:00:00Z", tz, 2),
+            vec![utc("2026-11-02T14:00:00Z"), utc("2026-11-09T14:00:00Z")]
+        );
+        // 土曜の23:30はUTCでは日曜になるが、現地の曜日で数える
+        assert_eq!(
+            occurrences("FREQ=WEEKLY;BYDAY=SA", "2026-10-25T03:30:00Z", tz, 2),
+            vec![utc("2026-11-01T03:30:00Z"), utc("2026-11-08T04:30:00Z")]
+        );
+    }
+
+    #[test]
+    fn should_resolve_skipped_and_repeated_local_times() {
+        let tz = chrono_tz::Europe::Berlin;
+        // 02:30は2026-03-29には存在しないので、切り替え前の+01:00で解釈して03:30になる
+        assert_eq!(
+            next("FREQ=DAILY", "2026-03-28T01:30:00Z", tz).unwrap().0,
+            utc("2026-03-29T01:30:00Z")
+        );
+        assert_eq!(
+            utc("2026-03-29T01:30:00Z")
+                .with_timezone(&tz)
+                .format("%H:%M")
+                .to_string(),
+            "03:30"
+        );
+        // 02:30は2026-10-25に2回あるので、早い方(+02:00)を使う
+        assert_eq!(
+            next("FREQ=DAILY", "2026-10-24T00:30:00Z", tz).unwrap().0,
+            utc("2026-10-25T00:30:00Z")
+        );
+        assert_eq!(
+            next("FREQ=DAILY", "2026-10-25T00:30:00Z", tz).unwrap().0,
+            utc("2026-10-26T01:30:00Z")
+        );
+
+        let tz = chrono_tz::America::New_York;
+        assert_eq!(
+            next("FREQ=DAILY", "2026-03-07T07:30:00Z", tz).unwrap().0,
+            utc("2026-03-08T07:30:00Z")
+        );
+        assert_eq!(
+            next("FREQ=DAILY", "2026-10-31T05:30:00Z", tz).unwrap().0,
+            utc("2026-11-01T05:30:00Z")
+        );
+    }
+}
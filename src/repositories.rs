@@ -1,18 +1,75 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::FromRow;
 
+use std::{cmp::Ordering, collections::HashMap};
 use thiserror::Error;
-use validator::Validate;
+use tracing::warn;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+use crate::recurrence::RRule;
+
+pub mod memory;
+pub mod postgres;
+pub mod sqlite;
+pub use memory::TodoRepositoryForMemory;
+pub use postgres::TodoRepositoryForDB;
+pub use sqlite::TodoRepositoryForSqlite;
 
 // 汎用的なエラーメッセージをここに集結させる。
 #[derive(Debug, Error)]
-enum RepositoryError {
+pub enum RepositoryError {
     #[error("Unexpected Error: {0}")]
     Unexpected(String),
     #[error("NotFound, id is {0}")]
     NotFound(i32),
+    #[error("Conflict: {0}")]
+    Conflict(String),
+    #[error("Validation Error: {0}")]
+    Validation(#[from] ValidationErrors),
+    #[error("Unavailable: {0}")]
+    Unavailable(String),
+    #[error("PreconditionFailed, todo {0} has been modified")]
+    PreconditionFailed(i32),
+    #[error("IdempotencyKeyReused, key {0} was used for a different request")]
+    IdempotencyKeyReused(String),
+}
+
+// リポジトリはanyhow::Resultを返すので、中身がRepositoryErrorやsqlx::Errorならそれを取り出す。
+impl From<anyhow::Error> for RepositoryError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<RepositoryError>() {
+            Ok(e) => e,
+            Err(e) => match e.downcast::<sqlx::Error>() {
+                Ok(e) => e.into(),
+                Err(e) => RepositoryError::Unexpected(e.to_string()),
+            },
+        }
+    }
+}
+
+impl From<sqlx::Error> for RepositoryError {
+    fn from(e: sqlx::Error) -> Self {
+        match &e {
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => {
+                RepositoryError::Unavailable(e.to_string())
+            }
+            sqlx::Error::Database(db_error) => match db_error.code().as_deref() {
+                // Postgres: unique_violation, foreign_key_violation
+                // SQLite: SQLITE_CONSTRAINT_UNIQUE, SQLITE_CONSTRAINT_PRIMARYKEY, SQLITE_CONSTRAINT_FOREIGNKEY
+                // DBのメッセージには制約名などのスキーマが含まれるので、ログにだけ残す。
+                Some("23505" | "23503" | "2067" | "1555" | "787") => {
+                    warn!("constraint violation: {}", db_error.message());
+                    RepositoryError::Conflict("conflicts with existing data".to_string())
+                }
+                _ => RepositoryError::Unexpected(e.to_string()),
+            },
+            _ => RepositoryError::Unexpected(e.to_string()),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Validate)]
@@ -20,341 +77,1048 @@ pub struct CreateTodo {
     #[validate(length(min = 1, message = "Can not be empty"))]
     #[validate(length(max = 100, message = "Over text length"))]
     pub text: String,
+    #[serde(default)]
+    pub due_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub priority: Priority,
+    #[serde(default)]
+    pub list_id: Option<i32>,
+    #[serde(default)]
+    pub parent_id: Option<i32>,
+    #[serde(default)]
+    #[validate(custom = "validate_recurrence")]
+    pub recurrence: Option<String>,
+    #[serde(default)]
+    #[validate(custom = "validate_time_zone")]
+    pub time_zone: Option<String>,
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Validate)]
+impl CreateTodo {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            due_at: None,
+            priority: Priority::default(),
+            list_id: None,
+            parent_id: None,
+            recurrence: None,
+            time_zone: None,
+        }
+    }
+}
+
+// due_at、list_id、parent_id、recurrence、time_zoneは省略すると変更せず、nullを指定すると外す。
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Validate)]
 pub struct UpdateTodo {
     #[validate(length(min = 1, message = "Can not be empty"))]
-    #[validate(length(min = 100, message = "Over text length"))]
+    #[validate(length(max = 100, message = "Over text length"))]
     pub text: Option<String>,
     pub completed: Option<bool>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub due_at: Option<Option<DateTime<Utc>>>,
+    pub priority: Option<Priority>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub list_id: Option<Option<i32>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub parent_id: Option<Option<i32>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[validate(custom = "validate_recurrence")]
+    pub recurrence: Option<Option<String>>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_nullable",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[validate(custom = "validate_time_zone")]
+    pub time_zone: Option<Option<String>>,
+}
+
+// 繰り返しの規則はRFC 5545のRRULE形式(例: FREQ=WEEKLY;BYDAY=MO,FR)で指定する。
+fn validate_recurrence(recurrence: &str) -> Result<(), ValidationError> {
+    recurrence.parse::<RRule>().map(|_| ()).map_err(|e| {
+        let mut error = ValidationError::new("rrule");
+        error.message = Some(e.to_string().into());
+        error
+    })
+}
+
+// タイムゾーンはIANAの名前(例: Asia/Tokyo)で指定する。省略時はUTC。
+fn validate_time_zone(time_zone: &str) -> Result<(), ValidationError> {
+    time_zone.parse::<Tz>().map(|_| ()).map_err(|_| {
+        let mut error = ValidationError::new("time_zone");
+        error.message = Some(format!("unknown time zone [{time_zone}]").into());
+        error
+    })
+}
+
+// 値があればSomeで包む。フィールドの省略(None)とnull(Some(None))を区別するために使う。
+fn deserialize_nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+// 優先度。低い順に並んでいて、DBには0(none)から4(urgent)の数値で保存する。
+#[derive(
+    Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[repr(i16)]
+pub enum Priority {
+    #[default]
+    None = 0,
+    Low = 1,
+    Medium = 2,
+    High = 3,
+    Urgent = 4,
 }
 
 // Todo そのものの構造体
+// completed_atは完了にした日時で、未完了に戻すと消える。due_atは期限で、タイムゾーン付きで受け取りUTCで保存する。
+// list_idは所属するリストで、どのリストにも入っていない場合はNone。parent_idは親のTodoで、サブタスクの場合に入る。
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, FromRow)]
 pub struct Todo {
     pub id: i32,
     pub text: String,
     pub completed: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    pub due_at: Option<DateTime<Utc>>,
+    pub priority: Priority,
+    pub list_id: Option<i32>,
+    pub parent_id: Option<i32>,
+    pub recurrence: Option<String>,
+    pub time_zone: Option<String>,
+    // ゴミ箱に入れた日時。削除されていなければNone。
+    pub deleted_at: Option<DateTime<Utc>>,
+    // 変更するたびに増えるバージョン。ETagとして返す。
+    pub version: i32,
 }
 
 impl Todo {
     pub fn new(id: i32, text: String) -> Self {
+        let now = Utc::now();
         Self {
             id,
             text,
             completed: false,
+            created_at: now,
+            updated_at: now,
+            completed_at: None,
+            due_at: None,
+            priority: Priority::default(),
+            list_id: None,
+            parent_id: None,
+            recurrence: None,
+            time_zone: None,
+            deleted_at: None,
+            version: 1,
         }
     }
+
+    // 更新内容を反映してversionを進める。completedが切り替わった場合はcompleted_atも合わせて変える。
+    pub fn apply(&mut self, payload: UpdateTodo, now: DateTime<Utc>) {
+        if let Some(text) = payload.text {
+            self.text = text;
+        }
+        if let Some(completed) = payload.completed {
+            if completed && !self.completed {
+                self.completed_at = Some(now);
+            } else if !completed {
+                self.completed_at = None;
+            }
+            self.completed = completed;
+        }
+        if let Some(due_at) = payload.due_at {
+            self.due_at = due_at;
+        }
+        if let Some(priority) = payload.priority {
+            self.priority = priority;
+        }
+        if let Some(list_id) = payload.list_id {
+            self.list_id = list_id;
+        }
+        if let Some(parent_id) = payload.parent_id {
+            self.parent_id = parent_id;
+        }
+        if let Some(recurrence) = payload.recurrence {
+            self.recurrence = recurrence;
+        }
+        if let Some(time_zone) = payload.time_zone {
+            self.time_zone = time_zone;
+        }
+        self.updated_at = now;
+        self.version += 1;
+    }
+
+    // 繰り返しのTodoを完了にした時に作る次のTodo。期限がなければ完了日時から数える。
+    // 繰り返しが終わっている(UNTILを過ぎた、COUNTを使い切った)場合はNone。
+    pub fn next_occurrence(&self) -> Option<CreateTodo> {
+        let rule: RRule = self.recurrence.as_deref()?.parse().ok()?;
+        let tz: Tz = self.time_zone.as_deref().unwrap_or("UTC").parse().ok()?;
+        let (due_at, rule) = rule.next(self.due_at.or(self.completed_at)?, tz)?;
+        Some(CreateTodo {
+            text: self.text.clone(),
+            due_at: Some(due_at),
+            priority: self.priority,
+            list_id: self.list_id,
+            parent_id: self.parent_id,
+            recurrence: Some(rule.to_string()),
+            time_zone: self.time_zone.clone(),
+        })
+    }
 }
-// Todo　リポジトリインターフェース
-#[async_trait]
-pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, payload: CreateTodo) -> Result<Todo>;
-    async fn find(&self, id: i32) -> Result<Todo>;
-    async fn all(&self) -> Result<Vec<Todo>>;
-    async fn update(&self, id: i32, payload: UpdateTodo) -> Result<Todo>;
-    async fn delete(&self, id: i32) -> Result<()>;
+// 一覧取得のページング条件。cursorを指定した場合はそのidより古いものを返す（キーセット方式）。
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Validate)]
+#[validate(schema(function = "validate_pagination"))]
+pub struct Pagination {
+    #[validate(range(min = 1, max = 1000, message = "Out of range"))]
+    pub limit: Option<i64>,
+    #[validate(range(min = 0, message = "Can not be negative"))]
+    pub offset: Option<i64>,
+    pub cursor: Option<i32>,
 }
 
-#[derive(Debug, Clone)]
-pub struct TodoRepositoryForDB {
-    pool: PgPool,
+impl Pagination {
+    pub const DEFAULT_LIMIT: i64 = 100;
+
+    pub fn limit(&self) -> i64 {
+        self.limit.unwrap_or(Self::DEFAULT_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0)
+    }
 }
 
-impl TodoRepositoryForDB {
-    pub fn new(pool: PgPool) -> Self {
-        TodoRepositoryForDB { pool }
+fn validate_pagination(pagination: &Pagination) -> Result<(), ValidationError> {
+    if pagination.offset.is_some() && pagination.cursor.is_some() {
+        let mut error = ValidationError::new("exclusive");
+        error.message = Some("offset and cursor can not be used together".into());
+        return Err(error);
     }
+    Ok(())
 }
 
-#[async_trait]
-impl TodoRepository for TodoRepositoryForDB {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<Todo> {
-        dbg!(payload.text.clone());
-        let todo = sqlx::query_as::<_, Todo>(
-            r#"
-insert into todos (text, completed)
-values ($1, false)
-returning *;
-        "#,
-        )
-        .bind(payload.text.clone())
-        .fetch_one(&self.pool)
-        .await?;
-
-        Ok(todo)
-    }
-    async fn find(&self, id: i32) -> Result<Todo> {
-        let todo = sqlx::query_as::<_, Todo>(
-            r#"
-select * from todos where id=$1
-        "#,
-        )
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
-        Ok(todo)
-    }
-    async fn all(&self) -> Result<Vec<Todo>> {
-        let todos = sqlx::query_as::<_, Todo>(
-            r#"
-select * from todos
-order by id desc;
-        "#,
-        )
-        .fetch_all(&self.pool)
-        .await?;
-
-        Ok(todos)
-    }
-    async fn update(&self, id: i32, payload: UpdateTodo) -> Result<Todo> {
-        let old_todo = self.find(id).await?;
-        let todo = sqlx::query_as::<_, Todo>(
-            r#"
-update todos set text=$1, completed=$2
-where id=$3
-returning *
-        "#,
-        )
-        .bind(payload.text.unwrap_or(old_todo.text))
-        .bind(payload.completed.unwrap_or(old_todo.completed))
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await?;
-
-        Ok(todo)
-    }
-    async fn delete(&self, id: i32) -> Result<()> {
-        let _ = sqlx::query_as::<_, Todo>(
-            r#"
-delete from todos where id=$1
-        "#,
-        )
-        .bind(id)
-        .fetch_one(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        });
+// 一覧の絞り込みと並び順。`GET /todos?completed=false&q=milk&sort=-id` のように指定する。
+// 日時の範囲は`*_after`以上、`*_before`未満で絞り込む。
+// priorityは指定した優先度のもの、min_priorityは指定した優先度以上のものに絞り込む。
+// tagはタグ名で、そのタグが付いたものに絞り込む。list_idは指定したリストに入っているものに絞り込む。
+// parent_idは指定したTodoの直下のサブタスクに絞り込む。
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Validate)]
+pub struct TodoQuery {
+    pub completed: Option<bool>,
+    #[validate(length(max = 100, message = "Over text length"))]
+    pub q: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+    pub completed_after: Option<DateTime<Utc>>,
+    pub completed_before: Option<DateTime<Utc>>,
+    pub due_after: Option<DateTime<Utc>>,
+    pub due_before: Option<DateTime<Utc>>,
+    pub priority: Option<Priority>,
+    pub min_priority: Option<Priority>,
+    pub tag: Option<String>,
+    pub list_id: Option<i32>,
+    pub parent_id: Option<i32>,
+    pub sort: Option<TodoSort>,
+}
+
+impl TodoQuery {
+    pub fn sort(&self) -> TodoSort {
+        self.sort.unwrap_or_default()
+    }
 
-        Ok(())
+    // 日時の絞り込み条件を（カラム名, 比較演算子, 値）で返す。SQLの組み立てに使う。
+    pub fn date_ranges(&self) -> [(&'static str, &'static str, Option<DateTime<Utc>>); 8] {
+        [
+            ("created_at", ">=", self.created_after),
+            ("created_at", "<", self.created_before),
+            ("updated_at", ">=", self.updated_after),
+            ("updated_at", "<", self.updated_before),
+            ("completed_at", ">=", self.completed_after),
+            ("completed_at", "<", self.completed_before),
+            ("due_at", ">=", self.due_after),
+            ("due_at", "<", self.due_before),
+        ]
+    }
+
+    // メモリ上のTodoが条件に合うか。qは大文字小文字を区別しない部分一致。
+    // tagはTodo自体には持っていないので、呼び出し側で絞り込む。
+    pub fn matches(&self, todo: &Todo) -> bool {
+        let in_range = |value: Option<DateTime<Utc>>,
+                        after: Option<DateTime<Utc>>,
+                        before: Option<DateTime<Utc>>| {
+            after.is_none_or(|after| value.is_some_and(|value| value >= after))
+                && before.is_none_or(|before| value.is_some_and(|value| value < before))
+        };
+        self.completed
+            .is_none_or(|completed| todo.completed == completed)
+            && self
+                .priority
+                .is_none_or(|priority| todo.priority == priority)
+            && self
+                .min_priority
+                .is_none_or(|priority| todo.priority >= priority)
+            && self
+                .list_id
+                .is_none_or(|list_id| todo.list_id == Some(list_id))
+            && self
+                .parent_id
+                .is_none_or(|parent_id| todo.parent_id == Some(parent_id))
+            && self
+                .q
+                .as_ref()
+                .is_none_or(|q| todo.text.to_lowercase().contains(&q.to_lowercase()))
+            && in_range(
+                Some(todo.created_at),
+                self.created_after,
+                self.created_before,
+            )
+            && in_range(
+                Some(todo.updated_at),
+                self.updated_after,
+                self.updated_before,
+            )
+            && in_range(
+                todo.completed_at,
+                self.completed_after,
+                self.completed_before,
+            )
+            && in_range(todo.due_at, self.due_after, self.due_before)
     }
 }
 
-#[cfg(test)]
-pub mod test {
-    use crate::handler;
+// 並び順。先頭に`-`が付くものは降順。同じ値の場合はidの降順で並べる。
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TodoSort {
+    #[serde(rename = "id")]
+    IdAsc,
+    #[default]
+    #[serde(rename = "-id")]
+    IdDesc,
+    #[serde(rename = "text")]
+    TextAsc,
+    #[serde(rename = "-text")]
+    TextDesc,
+    #[serde(rename = "completed")]
+    CompletedAsc,
+    #[serde(rename = "-completed")]
+    CompletedDesc,
+    #[serde(rename = "created_at")]
+    CreatedAtAsc,
+    #[serde(rename = "-created_at")]
+    CreatedAtDesc,
+    #[serde(rename = "updated_at")]
+    UpdatedAtAsc,
+    #[serde(rename = "-updated_at")]
+    UpdatedAtDesc,
+    #[serde(rename = "completed_at")]
+    CompletedAtAsc,
+    #[serde(rename = "-completed_at")]
+    CompletedAtDesc,
+    #[serde(rename = "due_at")]
+    DueAtAsc,
+    #[serde(rename = "-due_at")]
+    DueAtDesc,
+    #[serde(rename = "priority")]
+    PriorityAsc,
+    #[serde(rename = "-priority")]
+    PriorityDesc,
+}
 
-    use super::*;
-    use actix_web::{
-        http::header::ContentType,
-        test,
-        web::{self},
-        App,
-    };
-    use dotenv::dotenv;
-    use pretty_assertions::assert_eq;
-    use sqlx::PgPool;
-    use tracing::{debug, instrument};
-    use tracing_subscriber::EnvFilter;
+impl TodoSort {
+    // SQLのorder by句。ユーザー入力を埋め込まないように固定の文字列だけを返す。
+    pub fn order_by(&self) -> &'static str {
+        match self {
+            TodoSort::IdAsc => "id asc",
+            TodoSort::IdDesc => "id desc",
+            TodoSort::TextAsc => "text asc, id desc",
+            TodoSort::TextDesc => "text desc, id desc",
+            TodoSort::CompletedAsc => "completed asc, id desc",
+            TodoSort::CompletedDesc => "completed desc, id desc",
+            TodoSort::CreatedAtAsc => "created_at asc, id desc",
+            TodoSort::CreatedAtDesc => "created_at desc, id desc",
+            TodoSort::UpdatedAtAsc => "updated_at asc, id desc",
+            TodoSort::UpdatedAtDesc => "updated_at desc, id desc",
+            // 未完了のものは昇順でも降順でも後ろに並べる。
+            TodoSort::CompletedAtAsc => "completed_at asc nulls last, id desc",
+            TodoSort::CompletedAtDesc => "completed_at desc nulls last, id desc",
+            // 期限のないものも同じく後ろに並べる。
+            TodoSort::DueAtAsc => "due_at asc nulls last, id desc",
+            TodoSort::DueAtDesc => "due_at desc nulls last, id desc",
+            TodoSort::PriorityAsc => "priority asc, id desc",
+            TodoSort::PriorityDesc => "priority desc, id desc",
+        }
+    }
 
-    use std::{env, sync::Once};
-    static INIT: Once = Once::new();
-    fn initialize_tracing() {
-        INIT.call_once(|| {
-            let log_level = env::var("RUST_LOG").unwrap_or("debug".into());
-            env::set_var("RUST_LOG", log_level);
-            tracing_subscriber::fmt()
-                .with_env_filter(EnvFilter::from_default_env())
-                .init();
-        });
+    // idだけで並べる場合はキーセット方式（cursor）でページングできる。
+    pub fn is_keyset(&self) -> bool {
+        matches!(self, TodoSort::IdAsc | TodoSort::IdDesc)
     }
-    // #[actix_web::test]
-    #[instrument(ret)]
-    async fn crud_scenario() {
-        initialize_tracing();
 
-        dotenv().ok();
+    // cursorより後ろにあるかどうか。
+    pub fn is_after_cursor(&self, id: i32, cursor: i32) -> bool {
+        match self {
+            TodoSort::IdAsc => id > cursor,
+            _ => id < cursor,
+        }
+    }
 
-        // DB接続
-        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+    pub fn compare(&self, a: &Todo, b: &Todo) -> Ordering {
+        let by_id_desc = b.id.cmp(&a.id);
+        match self {
+            TodoSort::IdAsc => a.id.cmp(&b.id),
+            TodoSort::IdDesc => by_id_desc,
+            TodoSort::TextAsc => a.text.cmp(&b.text).then(by_id_desc),
+            TodoSort::TextDesc => b.text.cmp(&a.text).then(by_id_desc),
+            TodoSort::CompletedAsc => a.completed.cmp(&b.completed).then(by_id_desc),
+            TodoSort::CompletedDesc => b.completed.cmp(&a.completed).then(by_id_desc),
+            TodoSort::CreatedAtAsc => a.created_at.cmp(&b.created_at).then(by_id_desc),
+            TodoSort::CreatedAtDesc => b.created_at.cmp(&a.created_at).then(by_id_desc),
+            TodoSort::UpdatedAtAsc => a.updated_at.cmp(&b.updated_at).then(by_id_desc),
+            TodoSort::UpdatedAtDesc => b.updated_at.cmp(&a.updated_at).then(by_id_desc),
+            TodoSort::CompletedAtAsc => {
+                nulls_last(a.completed_at, b.completed_at, false).then(by_id_desc)
+            }
+            TodoSort::CompletedAtDesc => {
+                nulls_last(a.completed_at, b.completed_at, true).then(by_id_desc)
+            }
+            TodoSort::DueAtAsc => nulls_last(a.due_at, b.due_at, false).then(by_id_desc),
+            TodoSort::DueAtDesc => nulls_last(a.due_at, b.due_at, true).then(by_id_desc),
+            TodoSort::PriorityAsc => a.priority.cmp(&b.priority).then(by_id_desc),
+            TodoSort::PriorityDesc => b.priority.cmp(&a.priority).then(by_id_desc),
+        }
+    }
+}
 
-        debug!("start connect database...");
-        let pool = PgPool::connect(database_url)
-            .await
-            .unwrap_or_else(|_| panic!("fail coonect database, usl is [{database_url}]"));
+// Noneを常に後ろにして比較する。
+fn nulls_last<T: Ord>(a: Option<T>, b: Option<T>, descending: bool) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) if descending => b.cmp(&a),
+        (Some(a), Some(b)) => a.cmp(&b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
 
-        //初期化
-        let repository = web::Data::new(TodoRepositoryForDB::new(pool));
+// LIKE検索用のパターンを作る。ワイルドカードはエスケープする（SQL側は escape '\' と合わせて使う）。
+pub(crate) fn like_pattern(q: &str) -> String {
+    let escaped = q
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{escaped}%")
+}
 
-        let app =
-            test::init_service(App::new().app_data(repository).configure(handler::config)).await;
+// ページングした結果。next_cursorは続きがある場合のみ入る。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: i64,
+    pub next_cursor: Option<i32>,
+}
 
-        let expected = Todo::new(1, "[crud_scenario] text".to_string());
+// ページに並べる要素。次のページのcursorにはidを使う。
+pub trait PageItem {
+    fn id(&self) -> i32;
+}
 
-        let actual = CreateTodo {
-            text: "[crud_scenario] text".to_string(),
+impl PageItem for Todo {
+    fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+impl<T: PageItem> Page<T> {
+    // limit+1件取得した結果から、続きの有無を判定してページを作る。
+    fn from_overfetched(mut items: Vec<T>, limit: i64, total: i64) -> Self {
+        let next_cursor = if items.len() as i64 > limit {
+            items.truncate(limit as usize);
+            items.last().map(PageItem::id)
+        } else {
+            None
         };
+        Page {
+            items,
+            total,
+            next_cursor,
+        }
+    }
+}
 
-        let req = test::TestRequest::post()
-            .uri("/todos")
-            .insert_header(ContentType::json())
-            .set_json(&actual)
-            .to_request();
+// 期限が近いTodoの条件。今からdays日以内に期限が来るものを返す。
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Validate)]
+pub struct UpcomingQuery {
+    #[validate(range(min = 1, max = 365, message = "Out of range"))]
+    pub days: Option<i64>,
+}
+
+impl UpcomingQuery {
+    pub const DEFAULT_DAYS: i64 = 7;
 
-        let resp: Todo = test::call_and_read_body_json(&app, req).await;
-        assert_eq!(expected, resp);
+    pub fn days(&self) -> i64 {
+        self.days.unwrap_or(Self::DEFAULT_DAYS)
     }
 }
 
-#[cfg(test)]
-pub mod test_utils {
-    use super::*;
-    use anyhow::{Context, Result};
-    use async_trait::async_trait;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    };
+// 全文検索の条件
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq, Validate)]
+pub struct SearchQuery {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    pub q: String,
+    pub mode: Option<SearchMode>,
+}
+
+// 検索方法。wordsは単語単位の全文検索、ngramは文字のn-gramによる部分一致検索。
+// 日本語のように単語が空白で区切られない文章はngramで検索する。
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    #[default]
+    Words,
+    Ngram,
+}
 
-    impl CreateTodo {
-        pub fn new(text: String) -> Self {
-            Self { text }
+// 検索語に一致した部分を<mark>で囲む。大文字小文字は区別しない。
+pub(crate) fn highlight_substring(text: &str, q: &str) -> String {
+    let mut snippet = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(len) = match_prefix(rest, q) {
+            snippet.push_str(HIGHLIGHT_START);
+            snippet.push_str(&rest[..len]);
+            snippet.push_str(HIGHLIGHT_STOP);
+            rest = &rest[len..];
+        } else {
+            let ch = rest.chars().next().unwrap_or_default();
+            snippet.push(ch);
+            rest = &rest[ch.len_utf8()..];
         }
     }
-    type TodoDatas = HashMap<i32, Todo>;
+    snippet
+}
 
-    //メモリ上にTodoリストを保存するための構造体
-    #[derive(Debug, Clone)]
-    pub struct TodoRepositoryForMemory {
-        store: Arc<RwLock<TodoDatas>>,
+// textがqで始まっていれば、一致したバイト数を返す。
+fn match_prefix(text: &str, q: &str) -> Option<usize> {
+    if q.is_empty() {
+        return None;
+    }
+    let mut chars = text.char_indices();
+    let mut len = 0;
+    for q_char in q.chars() {
+        let (i, ch) = chars.next()?;
+        if !ch.to_lowercase().eq(q_char.to_lowercase()) {
+            return None;
+        }
+        len = i + ch.len_utf8();
     }
+    Some(len)
+}
 
-    impl TodoRepositoryForMemory {
-        pub fn new() -> Self {
-            TodoRepositoryForMemory {
-                store: Arc::default(),
+// 全文検索の結果。rankが大きいほど一致度が高く、snippetは一致箇所を<mark>で囲んだ本文。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, FromRow)]
+pub struct TodoSearchHit {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub todo: Todo,
+    pub rank: f64,
+    pub snippet: String,
+}
+
+impl PageItem for TodoSearchHit {
+    fn id(&self) -> i32 {
+        self.todo.id
+    }
+}
+
+pub const HIGHLIGHT_START: &str = "<mark>";
+pub const HIGHLIGHT_STOP: &str = "</mark>";
+
+// タグ。名前は重複できない。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct Tag {
+    pub id: i32,
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Validate)]
+pub struct CreateTag {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 50, message = "Over name length"))]
+    pub name: String,
+}
+
+impl CreateTag {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Validate)]
+pub struct UpdateTag {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 50, message = "Over name length"))]
+    pub name: String,
+}
+
+// Todoにタグを付ける際のリクエストボディ
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AttachTag {
+    pub tag_id: i32,
+}
+
+// サブタスクの進み具合。doneは完了した子の数、totalは子の数。
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub done: i64,
+    pub total: i64,
+}
+
+// 進み具合を付けたTodo。`GET /todos/{id}`で返す。
+// blockedは未完了のTodoにブロックされている場合にtrueになる。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TodoDetail {
+    #[serde(flatten)]
+    pub todo: Todo,
+    pub progress: Progress,
+    pub blocked: bool,
+}
+
+// Todoの依存関係を追加する際のリクエストボディ。blocker_idのTodoが完了するまで完了にできなくなる。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddDependency {
+    pub blocker_id: i32,
+}
+
+// サブタスクを入れ子にしたTodo。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TodoTree {
+    #[serde(flatten)]
+    pub todo: Todo,
+    pub progress: Progress,
+    pub children: Vec<TodoTree>,
+}
+
+impl TodoTree {
+    // 根とその子孫のTodoから木を組み立てる。子はid順に並べる。
+    pub fn build(root: Todo, descendants: Vec<Todo>) -> Self {
+        let mut children: HashMap<i32, Vec<Todo>> = HashMap::new();
+        for todo in descendants {
+            if let Some(parent_id) = todo.parent_id {
+                children.entry(parent_id).or_default().push(todo);
             }
         }
+        Self::grow(root, &mut children)
+    }
 
-        fn write_store_ref(&self) -> RwLockWriteGuard<TodoDatas> {
-            self.store.write().unwrap()
+    fn grow(todo: Todo, children: &mut HashMap<i32, Vec<Todo>>) -> Self {
+        let mut nodes: Vec<TodoTree> = children
+            .remove(&todo.id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|child| Self::grow(child, children))
+            .collect();
+        nodes.sort_by_key(|node| node.todo.id);
+        let progress = Progress {
+            done: nodes.iter().filter(|node| node.todo.completed).count() as i64,
+            total: nodes.len() as i64,
+        };
+        TodoTree {
+            todo,
+            progress,
+            children: nodes,
         }
+    }
+}
+
+// 一括操作の1件。`{"op": "create", "todo": {...}}`のようにopで種類を分ける。
+// versionはIf-Matchと同じで、指定した場合はTodoのversionと違うと失敗する。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BulkOperation {
+    Create {
+        todo: CreateTodo,
+    },
+    Update {
+        id: i32,
+        version: Option<i32>,
+        todo: UpdateTodo,
+    },
+    Delete {
+        id: i32,
+        version: Option<i32>,
+    },
+}
 
-        fn read_store_ref(&self) -> RwLockReadGuard<TodoDatas> {
-            self.store.read().unwrap()
+impl Validate for BulkOperation {
+    fn validate(&self) -> Result<(), ValidationErrors> {
+        match self {
+            BulkOperation::Create { todo } => todo.validate(),
+            BulkOperation::Update { todo, .. } => todo.validate(),
+            BulkOperation::Delete { .. } => Ok(()),
         }
     }
+}
 
-    impl Default for TodoRepositoryForMemory {
-        fn default() -> Self {
-            Self::new()
-        }
+// 一括操作1件の結果。作成、更新したTodoを返し、削除の場合はNoneになる。
+pub type BulkResult = Result<Option<Todo>, RepositoryError>;
+
+// 履歴の種類
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "text", rename_all = "lowercase")]
+pub enum TodoEventKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+}
+
+// Todoの変更履歴。textとcompletedの変更前(old)と変更後(new)の値、操作した人(actor)を残す。
+// 作成した時はold、削除した時はnewがNoneになる。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct TodoEvent {
+    pub id: i32,
+    pub todo_id: i32,
+    pub kind: TodoEventKind,
+    pub old_text: Option<String>,
+    pub new_text: Option<String>,
+    pub old_completed: Option<bool>,
+    pub new_completed: Option<bool>,
+    pub actor: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// 履歴に書き込む変更。idと日時は書き込む時に決まる。
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TodoChange<'a> {
+    pub todo_id: i32,
+    pub kind: TodoEventKind,
+    pub old: Option<&'a Todo>,
+    pub new: Option<&'a Todo>,
+}
+
+impl<'a> TodoChange<'a> {
+    pub fn created(todo: &'a Todo) -> Self {
+        Self::new(TodoEventKind::Created, None, todo)
     }
 
-    #[async_trait]
-    impl TodoRepository for TodoRepositoryForMemory {
-        async fn create(&self, payload: CreateTodo) -> Result<Todo> {
-            let mut store = self.write_store_ref();
-            // Todo: 例えば、idが2のtodoが1つしかない場合は、いくらcreateしてもtodoが作れないバグがある。
-            let id = (store.len() + 1) as i32;
-            let todo = Todo::new(id, payload.text);
-            store.insert(id, todo.clone());
-            Ok(todo)
+    pub fn updated(old: &'a Todo, new: &'a Todo) -> Self {
+        Self {
+            todo_id: new.id,
+            kind: TodoEventKind::Updated,
+            old: Some(old),
+            new: Some(new),
         }
+    }
 
-        async fn find(&self, id: i32) -> Result<Todo> {
-            let store = self.read_store_ref();
-            let todo = store
-                .get(&id)
-                .cloned()
-                .ok_or(RepositoryError::NotFound(id))?;
-            Ok(todo)
-        }
+    pub fn deleted(todo: &'a Todo) -> Self {
+        Self::new(TodoEventKind::Deleted, Some(todo), todo)
+    }
+
+    pub fn restored(todo: &'a Todo) -> Self {
+        Self::new(TodoEventKind::Restored, None, todo)
+    }
 
-        async fn all(&self) -> Result<Vec<Todo>> {
-            let store = self.read_store_ref();
-            Ok(Vec::from_iter(store.values().cloned()))
+    pub fn purged(todo: &'a Todo) -> Self {
+        Self::new(TodoEventKind::Purged, Some(todo), todo)
+    }
+
+    // oldがSomeなら変更前の値だけ、NoneならTodoを変更後の値として持つ。
+    fn new(kind: TodoEventKind, old: Option<&'a Todo>, todo: &'a Todo) -> Self {
+        Self {
+            todo_id: todo.id,
+            kind,
+            old,
+            new: old.is_none().then_some(todo),
         }
+    }
+}
+
+// 親子関係や依存関係が循環してしまう場合のエラー
+pub(crate) fn cycle_error(field: &'static str, message: &'static str) -> RepositoryError {
+    let mut errors = ValidationErrors::new();
+    let mut error = ValidationError::new("cycle");
+    error.message = Some(message.into());
+    errors.add(field, error);
+    errors.into()
+}
+
+pub(crate) fn parent_cycle_error() -> RepositoryError {
+    cycle_error(
+        "parent_id",
+        "a todo can not be a subtask of itself or its subtasks",
+    )
+}
+
+pub(crate) fn blocker_cycle_error() -> RepositoryError {
+    cycle_error(
+        "blocker_id",
+        "a todo can not be blocked by itself or todos blocked by it",
+    )
+}
+
+// 同じ名前のタグは作れない。
+pub(crate) fn tag_exists_error(name: &str) -> RepositoryError {
+    RepositoryError::Conflict(format!("tag [{name}] already exists"))
+}
+
+// 親がゴミ箱にある間は、サブタスクだけを戻すことはできない。
+pub(crate) fn trashed_parent_error(id: i32, parent_id: i32) -> RepositoryError {
+    RepositoryError::Conflict(format!(
+        "todo {id} can not be restored while its parent {parent_id} is in the trash"
+    ))
+}
+
+// 未完了のTodoにブロックされている間は完了にできない。
+pub(crate) fn blocked_error(id: i32, open_blockers: i64) -> RepositoryError {
+    RepositoryError::Conflict(format!(
+        "todo {id} is blocked by {open_blockers} open todos"
+    ))
+}
+
+// Todoをまとめるリスト。open_countは未完了、done_countは完了したTodoの数。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct TodoList {
+    pub id: i32,
+    pub name: String,
+    pub open_count: i64,
+    pub done_count: i64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Validate)]
+pub struct CreateList {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over name length"))]
+    pub name: String,
+}
 
-        async fn update(&self, id: i32, payload: UpdateTodo) -> Result<Todo> {
-            let mut store = self.write_store_ref();
-            let todo = store.get(&id).context(RepositoryError::NotFound(id))?;
-            let text = payload.text.unwrap_or(todo.text.clone());
-            let completed = payload.completed.unwrap_or(todo.completed);
-            let todo = Todo {
-                id,
-                text,
+impl CreateList {
+    pub fn new(name: String) -> Self {
+        Self { name }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Validate)]
+pub struct UpdateList {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over name length"))]
+    pub name: String,
+}
+
+// リストを削除する時に、中のTodoをどうするか。
+// restrictはTodoが残っている場合は削除せず、cascadeはTodoも一緒に削除する。
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletePolicy {
+    #[default]
+    Restrict,
+    Cascade,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeleteListQuery {
+    pub policy: Option<DeletePolicy>,
+}
+
+// 条件に合うTodoをまとめて変更する時のクエリ。誤って全て消さないよう、confirm=trueを必須にする。
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfirmQuery {
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+// 一括操作のクエリ。atomicを省略した場合は全て成功するか全て取り消すかにする。
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BulkQuery {
+    pub atomic: Option<bool>,
+}
+
+// Todo　リポジトリインターフェース
+#[async_trait]
+pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn create(&self, payload: CreateTodo) -> Result<Todo>;
+    async fn find(&self, id: i32) -> Result<Todo>;
+    async fn all(&self, pagination: Pagination) -> Result<Page<Todo>> {
+        self.filter(TodoQuery::default(), pagination).await
+    }
+    async fn filter(&self, query: TodoQuery, pagination: Pagination) -> Result<Page<Todo>>;
+    async fn search(
+        &self,
+        query: SearchQuery,
+        pagination: Pagination,
+    ) -> Result<Page<TodoSearchHit>>;
+    // 期限を過ぎた未完了のTodoを期限の古い順に返す。
+    async fn overdue(&self, now: DateTime<Utc>, pagination: Pagination) -> Result<Page<Todo>> {
+        let query = TodoQuery {
+            completed: Some(false),
+            due_before: Some(now),
+            sort: Some(TodoSort::DueAtAsc),
+            ..Default::default()
+        };
+        self.filter(query, pagination).await
+    }
+    // nowからdays日以内に期限が来る未完了のTodoを期限の近い順に返す。
+    async fn upcoming(
+        &self,
+        now: DateTime<Utc>,
+        days: i64,
+        pagination: Pagination,
+    ) -> Result<Page<Todo>> {
+        let query = TodoQuery {
+            completed: Some(false),
+            due_after: Some(now),
+            due_before: Some(now + Duration::days(days)),
+            sort: Some(TodoSort::DueAtAsc),
+            ..Default::default()
+        };
+        self.filter(query, pagination).await
+    }
+    // Todoと全ての子孫を返す。先頭は指定したTodo自身で、子孫はid順に並べる。
+    async fn subtree(&self, id: i32) -> Result<Vec<Todo>>;
+    // 直下のサブタスクの進み具合
+    async fn progress(&self, id: i32) -> Result<Progress> {
+        let count = |completed: Option<bool>| {
+            let query = TodoQuery {
+                parent_id: Some(id),
                 completed,
+                ..Default::default()
             };
-            store.insert(id, todo.clone());
-            Ok(todo)
-        }
-
-        async fn delete(&self, id: i32) -> Result<()> {
-            // Todo: Create側のバグがあるので、そのバグをここで防ぐかどうか。
-            let mut store = self.write_store_ref();
-            store.remove(&id).ok_or(RepositoryError::NotFound(id))?;
-            Ok(())
-        }
+            let pagination = Pagination {
+                limit: Some(1),
+                ..Default::default()
+            };
+            self.filter(query, pagination)
+        };
+        Ok(Progress {
+            done: count(Some(true)).await?.total,
+            total: count(None).await?.total,
+        })
     }
+    // idのTodoをブロックしているTodoをid順に返す。
+    async fn blockers(&self, id: i32) -> Result<Vec<Todo>>;
+    // blocker_idのTodoにブロックされるようにして、ブロックしているTodoを返す。
+    // 循環する場合はエラーにする。既にある場合は何もしない。
+    async fn add_blocker(&self, id: i32, blocker_id: i32) -> Result<Vec<Todo>>;
+    async fn remove_blocker(&self, id: i32, blocker_id: i32) -> Result<()>;
+    // ブロックしているTodoが未完了の間は、完了にするとConflictになる。
+    // versionを指定した場合、Todoのversionと違えばPreconditionFailedになる。
+    async fn update(&self, id: i32, version: Option<i32>, payload: UpdateTodo) -> Result<Todo>;
+    // ゴミ箱に移す。サブタスクも一緒に移す。ゴミ箱のTodoはfindやfilterなどからは見えない。
+    async fn delete(&self, id: i32, version: Option<i32>) -> Result<()>;
+    // ゴミ箱のTodoを削除した新しい順に返す。
+    async fn trash(&self, pagination: Pagination) -> Result<Page<Todo>>;
+    // ゴミ箱から戻す。一緒にゴミ箱に入ったサブタスクも戻る。
+    async fn restore(&self, id: i32) -> Result<Todo>;
+    // ゴミ箱のTodoをサブタスクごと完全に削除する。
+    async fn purge(&self, id: i32) -> Result<()>;
+    // beforeより前にゴミ箱に入ったTodoを完全に削除して、削除した件数を返す。
+    async fn purge_trash(&self, before: DateTime<Utc>) -> Result<u64>;
+    // Todoの履歴を古い順に返す。作成、更新、削除などの変更と同じトランザクションで書き込まれる。
+    async fn history(&self, id: i32) -> Result<Vec<TodoEvent>>;
+    // 履歴に残す操作した人を設定したリポジトリを返す。
+    fn with_actor(&self, actor: Option<String>) -> Self;
+    // 一括操作を1つのトランザクションで順に実行して、操作ごとの結果を返す。
+    // atomicなら最初に失敗したところで止めて全てを戻し、結果はそこまでになる。
+    // atomicでなければ失敗した操作だけを戻して、残りを続ける。
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool) -> Result<Vec<BulkResult>>;
+    // 条件に合うTodoを全て同じ内容で更新して、更新した件数を返す。1件でも失敗すれば全て戻す。
+    async fn update_matching(&self, query: TodoQuery, payload: UpdateTodo) -> Result<u64>;
+    // 条件に合うTodoをサブタスクごとゴミ箱に移して、移した件数を返す。
+    async fn delete_matching(&self, query: TodoQuery) -> Result<u64>;
+}
 
-    mod test {
-        use super::*;
-
-        #[actix_web::test]
-        async fn todo_crud_scenario() {
-            let text = "todo test".to_string();
-            let id = 1;
-            let expected = Todo::new(id, text.clone());
-
-            //create : Todoを作成
-            let repository = TodoRepositoryForMemory::new();
-            let todo = repository
-                .create(CreateTodo { text })
-                .await
-                .expect("failed create todo.");
-            assert_eq!(expected, todo);
-
-            //find　：Todo idを取得
-            let todo = repository.find(todo.id).await.unwrap();
-            assert_eq!(expected, todo);
-
-            //all　全てのTodoを取得
-            let todo = repository.all().await.expect("failed get all todo.");
-            assert_eq!(vec![expected], todo);
-
-            // update　： Todoを更新
-            let text = "update todo text".to_string();
-            let todo = repository
-                .update(
-                    1,
-                    UpdateTodo {
-                        text: Some(text.clone()),
-                        completed: Some(true),
-                    },
-                )
-                .await
-                .expect("failed update todo.");
-
-            assert_eq!(
-                Todo {
-                    id,
-                    text,
-                    completed: true
-                },
-                todo
-            );
-
-            // delete　：Todoを削除
-            let res = repository.delete(id).await;
-            assert!(res.is_ok())
+// タグ　リポジトリインターフェース
+// TodoRepositoryと同じ構造体に実装するので、メソッド名が重ならないようにしている。
+#[async_trait]
+pub trait TagRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn create_tag(&self, payload: CreateTag) -> Result<Tag>;
+    async fn find_tag(&self, id: i32) -> Result<Tag>;
+    // 名前順に全てのタグを返す。
+    async fn all_tags(&self) -> Result<Vec<Tag>>;
+    async fn update_tag(&self, id: i32, payload: UpdateTag) -> Result<Tag>;
+    // タグを削除すると、Todoに付いていたものも外れる。
+    async fn delete_tag(&self, id: i32) -> Result<()>;
+    // Todoに付いているタグを名前順に返す。
+    async fn tags_of(&self, todo_id: i32) -> Result<Vec<Tag>>;
+    // タグを付けて、付いているタグを返す。既に付いている場合は何もしない。
+    async fn attach_tag(&self, todo_id: i32, tag_id: i32) -> Result<Vec<Tag>>;
+    async fn detach_tag(&self, todo_id: i32, tag_id: i32) -> Result<()>;
+}
+
+// Idempotency-Keyを保存するデフォルトの時間
+pub const IDEMPOTENCY_TTL_HOURS: i64 = 24;
+
+// Idempotency-Keyと、そのキーで受けたリクエストのハッシュとレスポンス。
+// statusとbodyがNoneのものは、まだ処理中。
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct IdempotencyKey {
+    pub key: String,
+    pub request_hash: String,
+    pub status: Option<i32>,
+    pub body: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl IdempotencyKey {
+    pub fn new(key: &str, request_hash: &str, created_at: DateTime<Utc>) -> Self {
+        Self {
+            key: key.to_string(),
+            request_hash: request_hash.to_string(),
+            status: None,
+            body: None,
+            created_at,
         }
     }
 }
+
+// Idempotency-Key　リポジトリインターフェース
+// キーは保存期間を過ぎると無いものとして扱う。
+#[async_trait]
+pub trait IdempotencyRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    // キーを予約する。保存期間内の同じキーが既にあれば、予約せずにそれを返す。
+    async fn reserve_key(&self, key: &str, request_hash: &str) -> Result<Option<IdempotencyKey>>;
+    // 予約したキーにレスポンスを保存する。
+    async fn save_response(&self, key: &str, status: u16, body: &str) -> Result<()>;
+    // レスポンスを保存していないキーを消して、同じキーでやり直せるようにする。
+    async fn release_key(&self, key: &str) -> Result<()>;
+    // 保存期間を過ぎたキーを消して、消した件数を返す。
+    async fn purge_keys(&self) -> Result<u64>;
+}
+
+// リスト　リポジトリインターフェース
+#[async_trait]
+pub trait ListRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    async fn create_list(&self, payload: CreateList) -> Result<TodoList>;
+    async fn find_list(&self, id: i32) -> Result<TodoList>;
+    // id順に全てのリストを返す。
+    async fn all_lists(&self) -> Result<Vec<TodoList>>;
+    async fn update_list(&self, id: i32, payload: UpdateList) -> Result<TodoList>;
+    // Todoが残っている場合、restrictではConflictになる。
+    async fn delete_list(&self, id: i32, policy: DeletePolicy) -> Result<()>;
+}
@@ -0,0 +1,1265 @@
+use super::{
+    blocked_error, blocker_cycle_error, highlight_substring, parent_cycle_error, tag_exists_error,
+    trashed_parent_error, BulkOperation, BulkResult, CreateList, CreateTag, CreateTodo,
+    DeletePolicy, IdempotencyKey, IdempotencyRepository, ListRepository, Page, Pagination,
+    RepositoryError, SearchMode, SearchQuery, Tag, TagRepository, Todo, TodoChange, TodoEvent,
+    TodoList, TodoQuery, TodoRepository, TodoSearchHit, UpdateList, UpdateTag, UpdateTodo,
+    HIGHLIGHT_START, HIGHLIGHT_STOP, IDEMPOTENCY_TTL_HOURS,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+type TodoDatas = BTreeMap<i32, Todo>;
+type NgramIndex = HashMap<(char, char), BTreeSet<i32>>;
+
+// メモリ上のデータ。idは削除されても再利用しないように、最後に払い出したidを保持する。
+// ngramsはn-gram検索用の転置インデックスで、文字のbigramからTodoのidを引く。
+// todo_tagsはTodoとタグの組を(todo_id, tag_id)で持つ。listsはリストのidと名前。
+// dependenciesは(todo_id, blocker_id)の組で、todo_idはblocker_idが完了するまで完了にできない。
+// 削除したTodoはtodosからtrashに移すので、todosには削除されていないものだけが入る。
+// eventsはTodoの変更履歴で、追記だけする。idempotency_keysはIdempotency-Keyからレスポンスを引く。
+#[derive(Debug, Default, Clone)]
+struct MemoryStore {
+    todos: TodoDatas,
+    trash: TodoDatas,
+    events: Vec<TodoEvent>,
+    last_id: i32,
+    ngrams: NgramIndex,
+    tags: BTreeMap<i32, Tag>,
+    last_tag_id: i32,
+    todo_tags: BTreeSet<(i32, i32)>,
+    lists: BTreeMap<i32, String>,
+    last_list_id: i32,
+    dependencies: BTreeSet<(i32, i32)>,
+    idempotency_keys: HashMap<String, IdempotencyKey>,
+}
+
+impl MemoryStore {
+    // Todoを保存する。同じidがあれば置き換え、インデックスも張り直す。
+    fn insert_todo(&mut self, todo: Todo) {
+        self.remove_todo(todo.id);
+        for bigram in bigrams(&todo.text) {
+            self.ngrams.entry(bigram).or_default().insert(todo.id);
+        }
+        self.todos.insert(todo.id, todo);
+    }
+
+    // 変更を履歴に残す。
+    fn record(&mut self, change: TodoChange<'_>, actor: Option<&str>) {
+        let event = TodoEvent {
+            id: self.events.len() as i32 + 1,
+            todo_id: change.todo_id,
+            kind: change.kind,
+            old_text: change.old.map(|todo| todo.text.clone()),
+            new_text: change.new.map(|todo| todo.text.clone()),
+            old_completed: change.old.map(|todo| todo.completed),
+            new_completed: change.new.map(|todo| todo.completed),
+            actor: actor.map(str::to_string),
+            created_at: Utc::now(),
+        };
+        self.events.push(event);
+    }
+
+    // 新しいidを振ってTodoを作る。
+    fn create_todo(
+        &mut self,
+        payload: CreateTodo,
+        actor: Option<&str>,
+    ) -> Result<Todo, RepositoryError> {
+        if let Some(list_id) = payload.list_id {
+            self.list(list_id)?;
+        }
+        if let Some(parent_id) = payload.parent_id {
+            if !self.todos.contains_key(&parent_id) {
+                return Err(RepositoryError::NotFound(parent_id));
+            }
+        }
+        self.last_id += 1;
+        let todo = Todo {
+            due_at: payload.due_at,
+            priority: payload.priority,
+            list_id: payload.list_id,
+            parent_id: payload.parent_id,
+            recurrence: payload.recurrence,
+            time_zone: payload.time_zone,
+            ..Todo::new(self.last_id, payload.text)
+        };
+        self.insert_todo(todo.clone());
+        self.record(TodoChange::created(&todo), actor);
+        Ok(todo)
+    }
+
+    fn remove_todo(&mut self, id: i32) -> Option<Todo> {
+        let todo = self.todos.remove(&id)?;
+        for bigram in bigrams(&todo.text) {
+            if let Some(ids) = self.ngrams.get_mut(&bigram) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.ngrams.remove(&bigram);
+                }
+            }
+        }
+        Some(todo)
+    }
+
+    // 条件に合うTodoを返す。
+    fn matching(&self, query: &TodoQuery) -> Vec<&Todo> {
+        self.todos
+            .values()
+            .filter(|todo| query.matches(todo))
+            .filter(|todo| {
+                query
+                    .tag
+                    .as_ref()
+                    .is_none_or(|tag| self.has_tag(todo.id, tag))
+            })
+            .collect()
+    }
+
+    fn matching_ids(&self, query: &TodoQuery) -> Vec<i32> {
+        let mut ids: Vec<i32> = self.matching(query).iter().map(|todo| todo.id).collect();
+        ids.sort();
+        ids
+    }
+
+    // Todoと全ての子孫を返す。先頭はTodo自身で、子孫はid順に並べる。
+    fn subtree(&self, id: i32) -> Result<Vec<Todo>, RepositoryError> {
+        if !self.todos.contains_key(&id) {
+            return Err(RepositoryError::NotFound(id));
+        }
+        let mut ids = tree_ids(&self.todos, id, |_| true);
+        // 親を後から付け替えると、子孫の方がidが小さいことがある。
+        ids.sort_by_key(|todo_id| (*todo_id != id, *todo_id));
+        Ok(ids.iter().map(|id| self.todos[id].clone()).collect())
+    }
+
+    // Todoをサブタスクごとゴミ箱に移す。
+    fn trash_tree(
+        &mut self,
+        id: i32,
+        now: DateTime<Utc>,
+        actor: Option<&str>,
+    ) -> Result<(), RepositoryError> {
+        for todo in self.subtree(id)? {
+            if let Some(mut todo) = self.remove_todo(todo.id) {
+                self.record(TodoChange::deleted(&todo), actor);
+                todo.deleted_at = Some(now);
+                todo.version += 1;
+                self.trash.insert(todo.id, todo);
+            }
+        }
+        Ok(())
+    }
+
+    // ゴミ箱から戻す。戻すのは一緒にゴミ箱に入ったサブタスクだけで、先に削除されていたものは残る。
+    fn restore_tree(&mut self, id: i32, actor: Option<&str>) -> Result<Todo, RepositoryError> {
+        let root = self.trash.get(&id).ok_or(RepositoryError::NotFound(id))?;
+        if let Some(parent_id) = root.parent_id {
+            if !self.todos.contains_key(&parent_id) {
+                return Err(trashed_parent_error(id, parent_id));
+            }
+        }
+        let deleted_at = root.deleted_at;
+        for id in tree_ids(&self.trash, id, |todo| todo.deleted_at == deleted_at) {
+            if let Some(mut todo) = self.trash.remove(&id) {
+                todo.deleted_at = None;
+                todo.version += 1;
+                self.record(TodoChange::restored(&todo), actor);
+                self.insert_todo(todo);
+            }
+        }
+        Ok(self.todos[&id].clone())
+    }
+
+    // ゴミ箱のTodoをサブタスクやタグとの関連ごと完全に削除して、削除した件数を返す。
+    fn remove_tree(&mut self, id: i32, actor: Option<&str>) -> usize {
+        let ids = tree_ids(&self.trash, id, |_| true);
+        for id in &ids {
+            if let Some(todo) = self.trash.remove(id) {
+                self.record(TodoChange::purged(&todo), actor);
+            }
+            self.todo_tags.retain(|&(todo_id, _)| todo_id != *id);
+            self.dependencies
+                .retain(|&(todo_id, blocker_id)| todo_id != *id && blocker_id != *id);
+        }
+        ids.len()
+    }
+
+    // サブタスクが全て完了した親を、上に向かって順に完了にする。完了にした親を返す。
+    fn complete_parents(
+        &mut self,
+        mut parent_id: Option<i32>,
+        now: DateTime<Utc>,
+        actor: Option<&str>,
+    ) -> Vec<Todo> {
+        let mut completed = vec![];
+        while let Some(id) = parent_id {
+            let Some(mut parent) = self.todos.get(&id).cloned() else {
+                break;
+            };
+            let all_done = self
+                .todos
+                .values()
+                .filter(|todo| todo.parent_id == Some(id))
+                .all(|todo| todo.completed);
+            if parent.completed || !all_done || !self.open_blockers(id).is_empty() {
+                break;
+            }
+            let payload = UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            };
+            let old = parent.clone();
+            parent.apply(payload, now);
+            parent_id = parent.parent_id;
+            self.record(TodoChange::updated(&old, &parent), actor);
+            self.insert_todo(parent.clone());
+            completed.push(parent);
+        }
+        completed
+    }
+
+    fn blockers(&self, id: i32) -> Vec<Todo> {
+        self.dependencies
+            .iter()
+            .filter(|(todo_id, _)| *todo_id == id)
+            .filter_map(|(_, blocker_id)| self.todos.get(blocker_id).cloned())
+            .collect()
+    }
+
+    fn open_blockers(&self, id: i32) -> Vec<Todo> {
+        let mut blockers = self.blockers(id);
+        blockers.retain(|todo| !todo.completed);
+        blockers
+    }
+
+    // idのTodoが、直接または間接的にblocker_idのTodoにブロックされているか。
+    fn is_blocked_by(&self, id: i32, blocker_id: i32) -> bool {
+        let mut visited = BTreeSet::new();
+        let mut stack = vec![id];
+        while let Some(current) = stack.pop() {
+            for &(todo_id, next) in &self.dependencies {
+                if todo_id != current || !visited.insert(next) {
+                    continue;
+                }
+                if next == blocker_id {
+                    return true;
+                }
+                stack.push(next);
+            }
+        }
+        false
+    }
+
+    // リストを件数と合わせて返す。
+    fn list(&self, id: i32) -> Result<TodoList, RepositoryError> {
+        let name = self.lists.get(&id).ok_or(RepositoryError::NotFound(id))?;
+        let (done, open): (Vec<&Todo>, Vec<&Todo>) = self
+            .todos
+            .values()
+            .filter(|todo| todo.list_id == Some(id))
+            .partition(|todo| todo.completed);
+        Ok(TodoList {
+            id,
+            name: name.clone(),
+            open_count: open.len() as i64,
+            done_count: done.len() as i64,
+        })
+    }
+
+    fn has_tag(&self, todo_id: i32, name: &str) -> bool {
+        self.todo_tags.iter().any(|&(id, tag_id)| {
+            id == todo_id && self.tags.get(&tag_id).is_some_and(|tag| tag.name == name)
+        })
+    }
+
+    fn tags_of(&self, todo_id: i32) -> Vec<Tag> {
+        let mut tags: Vec<Tag> = self
+            .todo_tags
+            .iter()
+            .filter(|(id, _)| *id == todo_id)
+            .filter_map(|(_, tag_id)| self.tags.get(tag_id).cloned())
+            .collect();
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+        tags
+    }
+
+    // 同じ名前のタグがあればConflictにする。idは自分自身を除くために使う。
+    fn check_tag_name(&self, name: &str, id: Option<i32>) -> Result<(), RepositoryError> {
+        if self
+            .tags
+            .values()
+            .any(|tag| tag.name == name && Some(tag.id) != id)
+        {
+            return Err(tag_exists_error(name));
+        }
+        Ok(())
+    }
+
+    // 検索語の全てのbigramを含むTodoを候補として返す。1文字の検索語は全件が候補になる。
+    fn ngram_candidates(&self, q: &str) -> Vec<&Todo> {
+        let query_bigrams = bigrams(q);
+        if query_bigrams.is_empty() {
+            return self.todos.values().collect();
+        }
+        let mut candidates: Option<BTreeSet<i32>> = None;
+        for bigram in &query_bigrams {
+            let Some(ids) = self.ngrams.get(bigram) else {
+                return vec![];
+            };
+            candidates = Some(match candidates {
+                Some(candidates) => candidates.intersection(ids).copied().collect(),
+                None => ids.clone(),
+            });
+        }
+        candidates
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|id| self.todos.get(id))
+            .collect()
+    }
+}
+
+// idのTodoと、followを満たすTodoをたどった子孫のidを幅優先で返す。
+fn tree_ids(todos: &TodoDatas, id: i32, follow: impl Fn(&Todo) -> bool) -> Vec<i32> {
+    if !todos.contains_key(&id) {
+        return vec![];
+    }
+    let mut ids = vec![id];
+    let mut i = 0;
+    while i < ids.len() {
+        let parent_id = ids[i];
+        ids.extend(
+            todos
+                .values()
+                .filter(|todo| todo.parent_id == Some(parent_id) && follow(todo))
+                .map(|todo| todo.id),
+        );
+        i += 1;
+    }
+    ids
+}
+
+// 小文字にした文字のbigramを重複なく返す。
+fn bigrams(text: &str) -> BTreeSet<(char, char)> {
+    let chars: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    chars.windows(2).map(|pair| (pair[0], pair[1])).collect()
+}
+
+//メモリ上にTodoリストを保存するための構造体
+#[derive(Debug, Clone)]
+pub struct TodoRepositoryForMemory {
+    store: Arc<RwLock<MemoryStore>>,
+    auto_complete_parent: bool,
+    actor: Option<String>,
+    idempotency_ttl: Duration,
+}
+
+impl TodoRepositoryForMemory {
+    pub fn new() -> Self {
+        TodoRepositoryForMemory {
+            store: Arc::default(),
+            auto_complete_parent: false,
+            actor: None,
+            idempotency_ttl: Duration::hours(IDEMPOTENCY_TTL_HOURS),
+        }
+    }
+
+    // 全てのサブタスクが完了した時に、親も完了にするかどうか。
+    pub fn with_auto_complete_parent(mut self, enabled: bool) -> Self {
+        self.auto_complete_parent = enabled;
+        self
+    }
+
+    // Idempotency-Keyを保存する時間
+    pub fn with_idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = ttl;
+        self
+    }
+
+    fn update_todo(
+        &self,
+        store: &mut MemoryStore,
+        id: i32,
+        version: Option<i32>,
+        payload: UpdateTodo,
+    ) -> Result<Todo, RepositoryError> {
+        if let Some(Some(list_id)) = payload.list_id {
+            store.list(list_id)?;
+        }
+        if let Some(Some(parent_id)) = payload.parent_id {
+            if !store.todos.contains_key(&parent_id) {
+                return Err(RepositoryError::NotFound(parent_id));
+            }
+            // 自分自身や子孫を親にすると循環してしまう。
+            if store.subtree(id)?.iter().any(|todo| todo.id == parent_id) {
+                return Err(parent_cycle_error());
+            }
+        }
+        let mut todo = store
+            .todos
+            .get(&id)
+            .cloned()
+            .ok_or(RepositoryError::NotFound(id))?;
+        if version.is_some_and(|version| version != todo.version) {
+            return Err(RepositoryError::PreconditionFailed(id));
+        }
+        if payload.completed == Some(true) && !todo.completed {
+            let open_blockers = store.open_blockers(id).len() as i64;
+            if open_blockers > 0 {
+                return Err(blocked_error(id, open_blockers));
+            }
+        }
+        let old = todo.clone();
+        let now = Utc::now();
+        let actor = self.actor.as_deref();
+        todo.apply(payload, now);
+        store.insert_todo(todo.clone());
+        store.record(TodoChange::updated(&old, &todo), actor);
+        if todo.completed {
+            self.after_completed(store, &old, &todo, now)?;
+        }
+        Ok(todo)
+    }
+
+    // 完了にしたTodoと、それで自動的に完了になった親について、繰り返しの次の回を作る。
+    // 次の回は未完了のサブタスクになるので、親を完了にするかを決めてから作る。
+    fn after_completed(
+        &self,
+        store: &mut MemoryStore,
+        old: &Todo,
+        todo: &Todo,
+        now: DateTime<Utc>,
+    ) -> Result<(), RepositoryError> {
+        let actor = self.actor.as_deref();
+        let mut completed = vec![];
+        if !old.completed {
+            completed.push(todo.clone());
+        }
+        if self.auto_complete_parent {
+            completed.extend(store.complete_parents(todo.parent_id, now, actor));
+        }
+        for todo in completed {
+            if let Some(next) = todo.next_occurrence() {
+                store.create_todo(next, actor)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_todo(
+        &self,
+        store: &mut MemoryStore,
+        id: i32,
+        version: Option<i32>,
+    ) -> Result<(), RepositoryError> {
+        let todo = store.todos.get(&id).ok_or(RepositoryError::NotFound(id))?;
+        if version.is_some_and(|version| version != todo.version) {
+            return Err(RepositoryError::PreconditionFailed(id));
+        }
+        store.trash_tree(id, Utc::now(), self.actor.as_deref())?;
+        Ok(())
+    }
+
+    // 一括操作の1件を実行する。削除した場合はNoneを返す。
+    fn execute(
+        &self,
+        store: &mut MemoryStore,
+        operation: BulkOperation,
+    ) -> Result<Option<Todo>, RepositoryError> {
+        match operation {
+            BulkOperation::Create { todo } => {
+                store.create_todo(todo, self.actor.as_deref()).map(Some)
+            }
+            BulkOperation::Update { id, version, todo } => {
+                self.update_todo(store, id, version, todo).map(Some)
+            }
+            BulkOperation::Delete { id, version } => {
+                self.delete_todo(store, id, version).map(|_| None)
+            }
+        }
+    }
+
+    fn write_store_ref(&self) -> RwLockWriteGuard<'_, MemoryStore> {
+        self.store.write().unwrap()
+    }
+
+    fn read_store_ref(&self) -> RwLockReadGuard<'_, MemoryStore> {
+        self.store.read().unwrap()
+    }
+
+    // 単語単位の全文検索
+    fn search_words(&self, q: &str, pagination: Pagination) -> Page<TodoSearchHit> {
+        let store = self.read_store_ref();
+        let limit = pagination.limit();
+        let terms: Vec<String> = words(q).map(|(_, word)| word.to_lowercase()).collect();
+        if terms.is_empty() {
+            return Page::from_overfetched(vec![], limit, 0);
+        }
+
+        // 全ての単語を含むTodoを、一致した単語の割合が高い順に並べる。
+        let hits = store
+            .todos
+            .values()
+            .filter_map(|todo| {
+                let todo_words: Vec<String> = words(&todo.text)
+                    .map(|(_, word)| word.to_lowercase())
+                    .collect();
+                if !terms.iter().all(|term| todo_words.contains(term)) {
+                    return None;
+                }
+                let matched = todo_words.iter().filter(|w| terms.contains(w)).count();
+                Some(TodoSearchHit {
+                    rank: matched as f64 / todo_words.len() as f64,
+                    snippet: highlight(&todo.text, &terms),
+                    todo: todo.clone(),
+                })
+            })
+            .collect();
+        paginate_hits(hits, pagination)
+    }
+
+    // bigramの転置インデックスで候補を絞り込み、部分一致するTodoを返す。
+    // rankは本文のうち検索語が占める割合。
+    fn search_ngram(&self, q: &str, pagination: Pagination) -> Page<TodoSearchHit> {
+        let store = self.read_store_ref();
+        let q_lower = q.to_lowercase();
+        let hits = store
+            .ngram_candidates(q)
+            .into_iter()
+            .filter(|todo| todo.text.to_lowercase().contains(&q_lower))
+            .map(|todo| TodoSearchHit {
+                rank: q.chars().count() as f64 / todo.text.chars().count().max(1) as f64,
+                snippet: highlight_substring(&todo.text, q),
+                todo: todo.clone(),
+            })
+            .collect();
+        paginate_hits(hits, pagination)
+    }
+}
+
+impl Default for TodoRepositoryForMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl TodoRepository for TodoRepositoryForMemory {
+    async fn create(&self, payload: CreateTodo) -> Result<Todo> {
+        let mut store = self.write_store_ref();
+        Ok(store.create_todo(payload, self.actor.as_deref())?)
+    }
+
+    async fn find(&self, id: i32) -> Result<Todo> {
+        let store = self.read_store_ref();
+        let todo = store
+            .todos
+            .get(&id)
+            .cloned()
+            .ok_or(RepositoryError::NotFound(id))?;
+        Ok(todo)
+    }
+
+    async fn filter(&self, query: TodoQuery, pagination: Pagination) -> Result<Page<Todo>> {
+        let store = self.read_store_ref();
+        let sort = query.sort();
+        let limit = pagination.limit();
+
+        let mut todos = store.matching(&query);
+        todos.sort_by(|a, b| sort.compare(a, b));
+        let total = todos.len() as i64;
+
+        let todos = todos
+            .into_iter()
+            .filter(|todo| {
+                pagination
+                    .cursor
+                    .is_none_or(|cursor| sort.is_after_cursor(todo.id, cursor))
+            })
+            .skip(pagination.offset() as usize)
+            .take(limit as usize + 1)
+            .cloned()
+            .collect();
+        Ok(Page::from_overfetched(todos, limit, total))
+    }
+
+    async fn search(
+        &self,
+        query: SearchQuery,
+        pagination: Pagination,
+    ) -> Result<Page<TodoSearchHit>> {
+        Ok(match query.mode.unwrap_or_default() {
+            SearchMode::Words => self.search_words(&query.q, pagination),
+            SearchMode::Ngram => self.search_ngram(&query.q, pagination),
+        })
+    }
+
+    async fn update(&self, id: i32, version: Option<i32>, payload: UpdateTodo) -> Result<Todo> {
+        let mut store = self.write_store_ref();
+        Ok(self.update_todo(&mut store, id, version, payload)?)
+    }
+
+    async fn blockers(&self, id: i32) -> Result<Vec<Todo>> {
+        let store = self.read_store_ref();
+        if !store.todos.contains_key(&id) {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        Ok(store.blockers(id))
+    }
+
+    async fn add_blocker(&self, id: i32, blocker_id: i32) -> Result<Vec<Todo>> {
+        let mut store = self.write_store_ref();
+        for id in [id, blocker_id] {
+            if !store.todos.contains_key(&id) {
+                return Err(RepositoryError::NotFound(id).into());
+            }
+        }
+        // blocker_idが既にidにブロックされていると循環する。
+        if id == blocker_id || store.is_blocked_by(blocker_id, id) {
+            return Err(blocker_cycle_error().into());
+        }
+        store.dependencies.insert((id, blocker_id));
+        Ok(store.blockers(id))
+    }
+
+    async fn remove_blocker(&self, id: i32, blocker_id: i32) -> Result<()> {
+        let mut store = self.write_store_ref();
+        if !store.todos.contains_key(&id) {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        if !store.dependencies.remove(&(id, blocker_id)) {
+            return Err(RepositoryError::NotFound(blocker_id).into());
+        }
+        Ok(())
+    }
+
+    async fn subtree(&self, id: i32) -> Result<Vec<Todo>> {
+        Ok(self.read_store_ref().subtree(id)?)
+    }
+
+    async fn delete(&self, id: i32, version: Option<i32>) -> Result<()> {
+        let mut store = self.write_store_ref();
+        Ok(self.delete_todo(&mut store, id, version)?)
+    }
+
+    async fn trash(&self, pagination: Pagination) -> Result<Page<Todo>> {
+        let store = self.read_store_ref();
+        let limit = pagination.limit();
+        let mut todos: Vec<&Todo> = store.trash.values().collect();
+        todos.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(b.id.cmp(&a.id)));
+        let total = todos.len() as i64;
+        let todos = todos
+            .into_iter()
+            .skip(pagination.offset() as usize)
+            .take(limit as usize + 1)
+            .cloned()
+            .collect();
+        Ok(Page::from_overfetched(todos, limit, total))
+    }
+
+    async fn restore(&self, id: i32) -> Result<Todo> {
+        let mut store = self.write_store_ref();
+        Ok(store.restore_tree(id, self.actor.as_deref())?)
+    }
+
+    async fn purge(&self, id: i32) -> Result<()> {
+        let mut store = self.write_store_ref();
+        if store.remove_tree(id, self.actor.as_deref()) == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        Ok(())
+    }
+
+    async fn purge_trash(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut store = self.write_store_ref();
+        let ids: Vec<i32> = store
+            .trash
+            .values()
+            .filter(|todo| {
+                todo.deleted_at
+                    .is_some_and(|deleted_at| deleted_at < before)
+            })
+            .map(|todo| todo.id)
+            .collect();
+        // 親と一緒に削除済みのものは数えない。
+        let count = ids
+            .into_iter()
+            .map(|id| store.remove_tree(id, self.actor.as_deref()))
+            .sum::<usize>();
+        Ok(count as u64)
+    }
+
+    async fn history(&self, id: i32) -> Result<Vec<TodoEvent>> {
+        let store = self.read_store_ref();
+        let events: Vec<TodoEvent> = store
+            .events
+            .iter()
+            .filter(|event| event.todo_id == id)
+            .cloned()
+            .collect();
+        if events.is_empty() && !store.todos.contains_key(&id) {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        Ok(events)
+    }
+
+    fn with_actor(&self, actor: Option<String>) -> Self {
+        Self {
+            actor,
+            ..self.clone()
+        }
+    }
+
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool) -> Result<Vec<BulkResult>> {
+        let mut store = self.write_store_ref();
+        // トランザクションの代わりに変更前のデータを取っておき、失敗したら戻す。
+        // atomicなら全体の前、そうでなければ1件ごとの前のデータに戻す。
+        let mut snapshot = store.clone();
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            if !atomic {
+                snapshot = store.clone();
+            }
+            let result = self.execute(&mut store, operation);
+            let failed = result.is_err();
+            results.push(result);
+            if failed {
+                *store = std::mem::take(&mut snapshot);
+                if atomic {
+                    break;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    async fn update_matching(&self, query: TodoQuery, payload: UpdateTodo) -> Result<u64> {
+        let mut store = self.write_store_ref();
+        let ids = store.matching_ids(&query);
+        let snapshot = store.clone();
+        for id in &ids {
+            if let Err(err) = self.update_todo(&mut store, *id, None, payload.clone()) {
+                *store = snapshot;
+                return Err(err.into());
+            }
+        }
+        Ok(ids.len() as u64)
+    }
+
+    async fn delete_matching(&self, query: TodoQuery) -> Result<u64> {
+        let mut store = self.write_store_ref();
+        let now = Utc::now();
+        let before = store.trash.len();
+        for id in store.matching_ids(&query) {
+            // 親と一緒にゴミ箱に移したものは飛ばす。
+            if store.todos.contains_key(&id) {
+                store.trash_tree(id, now, self.actor.as_deref())?;
+            }
+        }
+        Ok((store.trash.len() - before) as u64)
+    }
+}
+
+#[async_trait]
+impl TagRepository for TodoRepositoryForMemory {
+    async fn create_tag(&self, payload: CreateTag) -> Result<Tag> {
+        let mut store = self.write_store_ref();
+        store.check_tag_name(&payload.name, None)?;
+        store.last_tag_id += 1;
+        let tag = Tag {
+            id: store.last_tag_id,
+            name: payload.name,
+        };
+        store.tags.insert(tag.id, tag.clone());
+        Ok(tag)
+    }
+
+    async fn find_tag(&self, id: i32) -> Result<Tag> {
+        let store = self.read_store_ref();
+        let tag = store
+            .tags
+            .get(&id)
+            .cloned()
+            .ok_or(RepositoryError::NotFound(id))?;
+        Ok(tag)
+    }
+
+    async fn all_tags(&self) -> Result<Vec<Tag>> {
+        let store = self.read_store_ref();
+        let mut tags: Vec<Tag> = store.tags.values().cloned().collect();
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(tags)
+    }
+
+    async fn update_tag(&self, id: i32, payload: UpdateTag) -> Result<Tag> {
+        let mut store = self.write_store_ref();
+        store.check_tag_name(&payload.name, Some(id))?;
+        let tag = store
+            .tags
+            .get_mut(&id)
+            .ok_or(RepositoryError::NotFound(id))?;
+        tag.name = payload.name;
+        Ok(tag.clone())
+    }
+
+    async fn delete_tag(&self, id: i32) -> Result<()> {
+        let mut store = self.write_store_ref();
+        store
+            .tags
+            .remove(&id)
+            .ok_or(RepositoryError::NotFound(id))?;
+        store.todo_tags.retain(|&(_, tag_id)| tag_id != id);
+        Ok(())
+    }
+
+    async fn tags_of(&self, todo_id: i32) -> Result<Vec<Tag>> {
+        let store = self.read_store_ref();
+        if !store.todos.contains_key(&todo_id) {
+            return Err(RepositoryError::NotFound(todo_id).into());
+        }
+        Ok(store.tags_of(todo_id))
+    }
+
+    async fn attach_tag(&self, todo_id: i32, tag_id: i32) -> Result<Vec<Tag>> {
+        let mut store = self.write_store_ref();
+        if !store.todos.contains_key(&todo_id) {
+            return Err(RepositoryError::NotFound(todo_id).into());
+        }
+        if !store.tags.contains_key(&tag_id) {
+            return Err(RepositoryError::NotFound(tag_id).into());
+        }
+        store.todo_tags.insert((todo_id, tag_id));
+        Ok(store.tags_of(todo_id))
+    }
+
+    async fn detach_tag(&self, todo_id: i32, tag_id: i32) -> Result<()> {
+        let mut store = self.write_store_ref();
+        if !store.todos.contains_key(&todo_id) {
+            return Err(RepositoryError::NotFound(todo_id).into());
+        }
+        if !store.todo_tags.remove(&(todo_id, tag_id)) {
+            return Err(RepositoryError::NotFound(tag_id).into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ListRepository for TodoRepositoryForMemory {
+    async fn create_list(&self, payload: CreateList) -> Result<TodoList> {
+        let mut store = self.write_store_ref();
+        store.last_list_id += 1;
+        let id = store.last_list_id;
+        store.lists.insert(id, payload.name);
+        Ok(store.list(id)?)
+    }
+
+    async fn find_list(&self, id: i32) -> Result<TodoList> {
+        Ok(self.read_store_ref().list(id)?)
+    }
+
+    async fn all_lists(&self) -> Result<Vec<TodoList>> {
+        let store = self.read_store_ref();
+        let lists = store
+            .lists
+            .keys()
+            .map(|&id| store.list(id))
+            .collect::<Result<_, _>>()?;
+        Ok(lists)
+    }
+
+    async fn update_list(&self, id: i32, payload: UpdateList) -> Result<TodoList> {
+        let mut store = self.write_store_ref();
+        let name = store
+            .lists
+            .get_mut(&id)
+            .ok_or(RepositoryError::NotFound(id))?;
+        *name = payload.name;
+        Ok(store.list(id)?)
+    }
+
+    async fn delete_list(&self, id: i32, policy: DeletePolicy) -> Result<()> {
+        let mut store = self.write_store_ref();
+        let list = store.list(id)?;
+        let ids: Vec<i32> = store
+            .todos
+            .values()
+            .filter(|todo| todo.list_id == Some(id))
+            .map(|todo| todo.id)
+            .collect();
+        match policy {
+            DeletePolicy::Restrict if !ids.is_empty() => {
+                let count = list.open_count + list.done_count;
+                return Err(RepositoryError::Conflict(format!(
+                    "list {id} still has {count} todos"
+                ))
+                .into());
+            }
+            DeletePolicy::Restrict => {}
+            DeletePolicy::Cascade => {
+                let now = Utc::now();
+                for todo_id in ids {
+                    // 先に親と一緒にゴミ箱に移っている場合がある。
+                    if store.todos.contains_key(&todo_id) {
+                        store.trash_tree(todo_id, now, self.actor.as_deref())?;
+                    }
+                }
+            }
+        }
+        // ゴミ箱のTodoはリストから外す。
+        for todo in store.trash.values_mut() {
+            if todo.list_id == Some(id) {
+                todo.list_id = None;
+                todo.version += 1;
+            }
+        }
+        store.lists.remove(&id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdempotencyRepository for TodoRepositoryForMemory {
+    async fn reserve_key(&self, key: &str, request_hash: &str) -> Result<Option<IdempotencyKey>> {
+        let mut store = self.write_store_ref();
+        let now = Utc::now();
+        let expired = store
+            .idempotency_keys
+            .get(key)
+            .is_some_and(|existing| existing.created_at < now - self.idempotency_ttl);
+        if !expired {
+            if let Some(existing) = store.idempotency_keys.get(key) {
+                return Ok(Some(existing.clone()));
+            }
+        }
+        store
+            .idempotency_keys
+            .insert(key.to_string(), IdempotencyKey::new(key, request_hash, now));
+        Ok(None)
+    }
+
+    async fn save_response(&self, key: &str, status: u16, body: &str) -> Result<()> {
+        let mut store = self.write_store_ref();
+        if let Some(reserved) = store.idempotency_keys.get_mut(key) {
+            reserved.status = Some(status.into());
+            reserved.body = Some(body.to_string());
+        }
+        Ok(())
+    }
+
+    async fn release_key(&self, key: &str) -> Result<()> {
+        let mut store = self.write_store_ref();
+        if store
+            .idempotency_keys
+            .get(key)
+            .is_some_and(|reserved| reserved.status.is_none())
+        {
+            store.idempotency_keys.remove(key);
+        }
+        Ok(())
+    }
+
+    async fn purge_keys(&self) -> Result<u64> {
+        let mut store = self.write_store_ref();
+        let before = Utc::now() - self.idempotency_ttl;
+        let count = store.idempotency_keys.len();
+        store
+            .idempotency_keys
+            .retain(|_, existing| existing.created_at >= before);
+        Ok((count - store.idempotency_keys.len()) as u64)
+    }
+}
+
+// rankの高い順に並べてページングする。
+fn paginate_hits(mut hits: Vec<TodoSearchHit>, pagination: Pagination) -> Page<TodoSearchHit> {
+    hits.sort_by(|a, b| b.rank.total_cmp(&a.rank).then(b.todo.id.cmp(&a.todo.id)));
+    let total = hits.len() as i64;
+    let limit = pagination.limit();
+    let hits = hits
+        .into_iter()
+        .skip(pagination.offset() as usize)
+        .take(limit as usize + 1)
+        .collect();
+    Page::from_overfetched(hits, limit, total)
+}
+
+// 英数字の連続を1単語として、本文中の位置と単語を返す。
+fn words(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| (word.as_ptr() as usize - text.as_ptr() as usize, word))
+}
+
+// 検索語に一致した単語を<mark>で囲む。
+fn highlight(text: &str, terms: &[String]) -> String {
+    let mut snippet = String::new();
+    let mut last = 0;
+    for (start, word) in words(text) {
+        if terms.contains(&word.to_lowercase()) {
+            snippet.push_str(&text[last..start]);
+            snippet.push_str(HIGHLIGHT_START);
+            snippet.push_str(word);
+            snippet.push_str(HIGHLIGHT_STOP);
+            last = start + word.len();
+        }
+    }
+    snippet.push_str(&text[last..]);
+    snippet
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[actix_web::test]
+    async fn todo_crud_scenario() {
+        let repository = TodoRepositoryForMemory::new();
+        let text = "todo test".to_string();
+
+        //create : Todoを作成
+        let expected = repository
+            .create(CreateTodo::new(text.clone()))
+            .await
+            .expect("failed create todo.");
+        assert_eq!(1, expected.id);
+        assert_eq!(text, expected.text);
+        assert!(!expected.completed);
+        assert_eq!(expected.created_at, expected.updated_at);
+        assert_eq!(None, expected.completed_at);
+
+        //find　：Todo idを取得
+        let todo = repository.find(expected.id).await.unwrap();
+        assert_eq!(expected, todo);
+
+        //all　全てのTodoを取得
+        let todos = repository
+            .all(Pagination::default())
+            .await
+            .expect("failed get all todo.");
+        assert_eq!(vec![expected.clone()], todos.items);
+
+        // update　： Todoを更新。完了にするとcompleted_atが入る
+        let todo = repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    text: Some("update todo text".to_string()),
+                    completed: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed update todo.");
+        assert_eq!(
+            Todo {
+                text: "update todo text".to_string(),
+                completed: true,
+                updated_at: todo.updated_at,
+                completed_at: todo.completed_at,
+                version: 2,
+                ..expected.clone()
+            },
+            todo
+        );
+        assert!(todo.updated_at >= expected.updated_at);
+        assert_eq!(Some(todo.updated_at), todo.completed_at);
+
+        // 未完了に戻すとcompleted_atが消える
+        let todo = repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    completed: Some(false),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed update todo.");
+        assert_eq!(None, todo.completed_at);
+        assert_eq!(expected.created_at, todo.created_at);
+
+        // delete　：Todoを削除
+        repository
+            .delete(1, None)
+            .await
+            .expect("failed delete todo.");
+        assert!(repository.find(1).await.is_err());
+        assert!(repository.delete(1, None).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn should_not_reuse_id_after_delete() {
+        let repository = TodoRepositoryForMemory::new();
+        for text in ["first", "second"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        repository.delete(1, None).await.unwrap();
+
+        let todo = repository
+            .create(CreateTodo::new("third".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(3, todo.id);
+
+        // 新しい順に並ぶ
+        let ids: Vec<i32> = repository
+            .all(Pagination::default())
+            .await
+            .unwrap()
+            .items
+            .into_iter()
+            .map(|todo| todo.id)
+            .collect();
+        assert_eq!(vec![3, 2], ids);
+    }
+
+    #[actix_web::test]
+    async fn should_search_by_words() {
+        let repository = TodoRepositoryForMemory::new();
+        for text in ["buy milk", "Milk and milk tea", "milkshake"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+
+        let page = repository
+            .search(
+                SearchQuery {
+                    q: "MILK".to_string(),
+                    mode: None,
+                },
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(2, page.total);
+        assert_eq!(2, page.items[0].todo.id);
+        assert_eq!(
+            "<mark>Milk</mark> and <mark>milk</mark> tea",
+            page.items[0].snippet
+        );
+        assert_eq!("buy <mark>milk</mark>", page.items[1].snippet);
+    }
+
+    #[actix_web::test]
+    async fn should_search_japanese_by_ngram() {
+        let repository = TodoRepositoryForMemory::new();
+        for text in ["牛乳を買う", "パンを買う", "牛"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        let search = |q: &str| SearchQuery {
+            q: q.to_string(),
+            mode: Some(SearchMode::Ngram),
+        };
+
+        let page = repository
+            .search(search("牛乳"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(1, page.total);
+        assert_eq!("<mark>牛乳</mark>を買う", page.items[0].snippet);
+
+        // 1文字でも検索できる
+        let page = repository
+            .search(search("牛"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![3, 1],
+            page.items.iter().map(|h| h.todo.id).collect::<Vec<_>>()
+        );
+
+        // 更新・削除するとインデックスからも消える
+        repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    text: Some("豆乳を買う".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        repository.delete(2, None).await.unwrap();
+        let page = repository
+            .search(search("を買う"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![1],
+            page.items.iter().map(|h| h.todo.id).collect::<Vec<_>>()
+        );
+        let page = repository
+            .search(search("牛乳"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(0, page.total);
+
+        // 単語検索では日本語の部分一致はできない
+        let page = repository
+            .search(
+                SearchQuery {
+                    q: "買う".to_string(),
+                    mode: None,
+                },
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(0, page.total);
+    }
+
+    #[actix_web::test]
+    async fn should_detach_tags_on_delete() {
+        let repository = TodoRepositoryForMemory::new();
+        let todo = repository
+            .create(CreateTodo::new("buy milk".to_string()))
+            .await
+            .unwrap();
+        let tag = repository
+            .create_tag(CreateTag::new("home".to_string()))
+            .await
+            .unwrap();
+        repository.attach_tag(todo.id, tag.id).await.unwrap();
+        // 更新してもタグは外れない
+        repository
+            .update(
+                todo.id,
+                None,
+                UpdateTodo {
+                    text: Some("buy bread".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![tag.clone()],
+            repository.tags_of(todo.id).await.unwrap()
+        );
+
+        // ゴミ箱にある間はタグが残り、完全に削除すると外れる
+        repository.delete(todo.id, None).await.unwrap();
+        assert!(repository.tags_of(todo.id).await.is_err());
+        assert!(!repository.read_store_ref().todo_tags.is_empty());
+        repository.purge(todo.id).await.unwrap();
+        assert!(repository.read_store_ref().todo_tags.is_empty());
+    }
+}
@@ -0,0 +1,2587 @@
+use super::{
+    blocked_error, blocker_cycle_error, highlight_substring, like_pattern, parent_cycle_error,
+    tag_exists_error, trashed_parent_error, BulkOperation, BulkResult, CreateList, CreateTag,
+    CreateTodo, DeletePolicy, IdempotencyKey, IdempotencyRepository, ListRepository, Page,
+    Pagination, RepositoryError, SearchMode, SearchQuery, Tag, TagRepository, Todo, TodoChange,
+    TodoEvent, TodoList, TodoQuery, TodoRepository, TodoSearchHit, TodoSort, UpdateList, UpdateTag,
+    UpdateTodo, HIGHLIGHT_START, HIGHLIGHT_STOP, IDEMPOTENCY_TTL_HOURS,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Acquire, QueryBuilder, Sqlite, SqlitePool, Transaction};
+
+// SQLiteのファイル1つで動かすためのリポジトリ。ローカル開発やCIで使う。
+#[derive(Debug, Clone)]
+pub struct TodoRepositoryForSqlite {
+    pool: SqlitePool,
+    auto_complete_parent: bool,
+    actor: Option<String>,
+    idempotency_ttl: Duration,
+}
+
+impl TodoRepositoryForSqlite {
+    pub fn new(pool: SqlitePool) -> Self {
+        TodoRepositoryForSqlite {
+            pool,
+            auto_complete_parent: false,
+            actor: None,
+            idempotency_ttl: Duration::hours(IDEMPOTENCY_TTL_HOURS),
+        }
+    }
+
+    // 全てのサブタスクが完了した時に、親も完了にするかどうか。
+    pub fn with_auto_complete_parent(mut self, enabled: bool) -> Self {
+        self.auto_complete_parent = enabled;
+        self
+    }
+
+    // Idempotency-Keyを保存する時間
+    pub fn with_idempotency_ttl(mut self, ttl: Duration) -> Self {
+        self.idempotency_ttl = ttl;
+        self
+    }
+
+    // SQLiteにはselect ... for updateが無いので、何も変えない書き込みで先に書き込みロックを取る。
+    // 読んでから書くまでの間に、他の接続の書き込みが割り込まなくなる。
+    async fn lock(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        sqlx::query(
+            r#"
+update todos set id=id where false
+        "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    // 削除されていないTodoをトランザクションの中で読む。
+    // 同時に変更されても順に適用されるよう、先に書き込みロックを取る。
+    async fn fetch(&self, tx: &mut Transaction<'_, Sqlite>, id: i32) -> Result<Todo> {
+        self.lock(tx).await?;
+        let todo = sqlx::query_as::<_, Todo>(
+            r#"
+select * from todos where id=$1 and deleted_at is null
+        "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        Ok(todo.ok_or(RepositoryError::NotFound(id))?)
+    }
+
+    // 一意制約の違反ではなくタグ名を示すConflictを返すため、先に同じ名前のタグを探す。
+    // idは自分自身を除くために使う。
+    async fn check_tag_name(&self, name: &str, id: Option<i32>) -> Result<()> {
+        let exists = sqlx::query(
+            r#"
+select id from tags where name=$1 and ($2 is null or id <> $2)
+        "#,
+        )
+        .bind(name)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .is_some();
+        if exists {
+            return Err(tag_exists_error(name).into());
+        }
+        Ok(())
+    }
+
+    // リストがあることをトランザクションの中で確かめる。
+    async fn check_list(&self, tx: &mut Transaction<'_, Sqlite>, id: i32) -> Result<()> {
+        sqlx::query(
+            r#"
+select id from lists where id=$1
+        "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(RepositoryError::NotFound(id))?;
+        Ok(())
+    }
+
+    async fn create_todo(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        payload: CreateTodo,
+    ) -> Result<Todo> {
+        if let Some(list_id) = payload.list_id {
+            self.check_list(tx, list_id).await?;
+        }
+        if let Some(parent_id) = payload.parent_id {
+            self.fetch(tx, parent_id).await?;
+        }
+        self.insert(tx, payload).await
+    }
+
+    async fn update_todo(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        id: i32,
+        version: Option<i32>,
+        payload: UpdateTodo,
+    ) -> Result<Todo> {
+        if let Some(Some(list_id)) = payload.list_id {
+            self.check_list(tx, list_id).await?;
+        }
+        if let Some(Some(parent_id)) = payload.parent_id {
+            self.fetch(tx, parent_id).await?;
+            // 自分自身や子孫を親にすると循環してしまう。
+            let (cycle,) = sqlx::query_as::<_, (bool,)>(
+                r#"
+with recursive subtree as (
+    select id from todos where id=$1 and deleted_at is null
+    union
+    select todos.id from todos join subtree on todos.parent_id = subtree.id
+    where todos.deleted_at is null
+)
+select exists(select 1 from subtree where id=$2)
+        "#,
+            )
+            .bind(id)
+            .bind(parent_id)
+            .fetch_one(&mut *tx)
+            .await?;
+            if cycle {
+                return Err(parent_cycle_error().into());
+            }
+        }
+        let mut todo = self.fetch(tx, id).await?;
+        if version.is_some_and(|version| version != todo.version) {
+            return Err(RepositoryError::PreconditionFailed(id).into());
+        }
+        if payload.completed == Some(true) && !todo.completed {
+            let open_blockers = self.open_blockers(tx, id).await?;
+            if open_blockers > 0 {
+                return Err(blocked_error(id, open_blockers).into());
+            }
+        }
+        let old = todo.clone();
+        todo.apply(payload, Utc::now());
+        let todo = self.save(tx, &todo).await?;
+        self.record(tx, TodoChange::updated(&old, &todo)).await?;
+        if todo.completed {
+            self.after_completed(tx, &old, &todo).await?;
+        }
+        Ok(todo)
+    }
+
+    // Todoをサブタスクごとゴミ箱に移す。
+    async fn trash_todo(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        id: i32,
+        version: Option<i32>,
+    ) -> Result<()> {
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+with recursive subtree as (
+    select id from todos where id=$1 and deleted_at is null and version=coalesce($3, version)
+    union
+    select todos.id from todos join subtree on todos.parent_id = subtree.id
+    where todos.deleted_at is null
+)
+update todos set deleted_at=$2, version=version+1
+where id in (select id from subtree) and deleted_at is null
+returning *
+        "#,
+        )
+        .bind(id)
+        .bind(Utc::now())
+        .bind(version)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        if todos.is_empty() {
+            let exists = sqlx::query(
+                r#"
+select id from todos where id=$1 and deleted_at is null
+        "#,
+            )
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .is_some();
+            return Err(if exists {
+                RepositoryError::PreconditionFailed(id)
+            } else {
+                RepositoryError::NotFound(id)
+            }
+            .into());
+        }
+        for todo in &todos {
+            self.record(tx, TodoChange::deleted(todo)).await?;
+        }
+        Ok(())
+    }
+
+    // 一括操作の1件を実行する。削除した場合はNoneを返す。
+    async fn execute(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        operation: BulkOperation,
+    ) -> Result<Option<Todo>> {
+        match operation {
+            BulkOperation::Create { todo } => self.create_todo(tx, todo).await.map(Some),
+            BulkOperation::Update { id, version, todo } => {
+                self.update_todo(tx, id, version, todo).await.map(Some)
+            }
+            BulkOperation::Delete { id, version } => {
+                self.trash_todo(tx, id, version).await.map(|_| None)
+            }
+        }
+    }
+
+    async fn insert(&self, tx: &mut Transaction<'_, Sqlite>, payload: CreateTodo) -> Result<Todo> {
+        let todo = sqlx::query_as::<_, Todo>(
+            r#"
+insert into todos (text, completed, created_at, updated_at, due_at, priority, list_id,
+    parent_id, recurrence, time_zone)
+values ($1, false, $2, $2, $3, $4, $5, $6, $7, $8)
+returning *;
+        "#,
+        )
+        .bind(payload.text)
+        .bind(Utc::now())
+        .bind(payload.due_at)
+        .bind(payload.priority)
+        .bind(payload.list_id)
+        .bind(payload.parent_id)
+        .bind(payload.recurrence)
+        .bind(payload.time_zone)
+        .fetch_one(&mut *tx)
+        .await?;
+        self.record(tx, TodoChange::created(&todo)).await?;
+        Ok(todo)
+    }
+
+    async fn save(&self, tx: &mut Transaction<'_, Sqlite>, todo: &Todo) -> Result<Todo> {
+        let saved = sqlx::query_as::<_, Todo>(
+            r#"
+update todos set text=$1, completed=$2, updated_at=$3, completed_at=$4, due_at=$5, priority=$6,
+    list_id=$7, parent_id=$8, recurrence=$9, time_zone=$10, version=$11
+where id=$12
+returning *
+        "#,
+        )
+        .bind(&todo.text)
+        .bind(todo.completed)
+        .bind(todo.updated_at)
+        .bind(todo.completed_at)
+        .bind(todo.due_at)
+        .bind(todo.priority)
+        .bind(todo.list_id)
+        .bind(todo.parent_id)
+        .bind(&todo.recurrence)
+        .bind(&todo.time_zone)
+        .bind(todo.version)
+        .bind(todo.id)
+        .fetch_one(&mut *tx)
+        .await?;
+        Ok(saved)
+    }
+
+    // 変更履歴を書き込む。変更と同じトランザクションの中で呼ぶ。
+    async fn record(&self, tx: &mut Transaction<'_, Sqlite>, change: TodoChange<'_>) -> Result<()> {
+        sqlx::query(
+            r#"
+insert into todo_events (todo_id, kind, old_text, new_text, old_completed, new_completed, actor,
+    created_at)
+values ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+        )
+        .bind(change.todo_id)
+        .bind(change.kind)
+        .bind(change.old.map(|todo| &todo.text))
+        .bind(change.new.map(|todo| &todo.text))
+        .bind(change.old.map(|todo| todo.completed))
+        .bind(change.new.map(|todo| todo.completed))
+        .bind(&self.actor)
+        .bind(Utc::now())
+        .execute(&mut *tx)
+        .await?;
+        Ok(())
+    }
+
+    // 完了にしたTodoと、それで自動的に完了になった親について、繰り返しの次の回を作る。
+    // 次の回は未完了のサブタスクになるので、親を完了にするかを決めてから作る。
+    async fn after_completed(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        old: &Todo,
+        todo: &Todo,
+    ) -> Result<()> {
+        let mut completed = vec![];
+        if !old.completed {
+            completed.push(todo.clone());
+        }
+        if self.auto_complete_parent {
+            completed.extend(self.complete_parents(tx, todo.parent_id).await?);
+        }
+        for todo in completed {
+            if let Some(next) = todo.next_occurrence() {
+                self.insert(tx, next).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // サブタスクが全て完了した親を、上に向かって順に完了にする。完了にした親を返す。
+    async fn complete_parents(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        mut parent_id: Option<i32>,
+    ) -> Result<Vec<Todo>> {
+        let mut completed = vec![];
+        while let Some(id) = parent_id {
+            let Some(mut parent) = sqlx::query_as::<_, Todo>(
+                r#"
+select * from todos where id=$1 and deleted_at is null
+        "#,
+            )
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?
+            else {
+                break;
+            };
+            let (done, total) = sqlx::query_as::<_, (i64, i64)>(
+                r#"
+select count(*) filter (where completed), count(*) from todos
+where parent_id=$1 and deleted_at is null
+        "#,
+            )
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+            if parent.completed || done < total || self.open_blockers(tx, id).await? > 0 {
+                break;
+            }
+            let old = parent.clone();
+            let payload = UpdateTodo {
+                completed: Some(true),
+                ..Default::default()
+            };
+            parent.apply(payload, Utc::now());
+            let parent = self.save(tx, &parent).await?;
+            self.record(tx, TodoChange::updated(&old, &parent)).await?;
+            parent_id = parent.parent_id;
+            completed.push(parent);
+        }
+        Ok(completed)
+    }
+
+    // migrations_sqlite配下のマイグレーションを適用する。
+    pub async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("./migrations_sqlite")
+            .run(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // idのTodoをブロックしている未完了のTodoの数
+    async fn open_blockers(&self, tx: &mut Transaction<'_, Sqlite>, id: i32) -> Result<i64> {
+        let (count,) = sqlx::query_as::<_, (i64,)>(
+            r#"
+select count(*) from todos
+join todo_dependencies on todo_dependencies.blocker_id = todos.id
+where todo_dependencies.todo_id=$1 and not todos.completed and todos.deleted_at is null
+        "#,
+        )
+        .bind(id)
+        .fetch_one(&mut *tx)
+        .await?;
+        Ok(count)
+    }
+
+    // 単語単位の全文検索
+    async fn search_words(&self, q: &str, pagination: Pagination) -> Result<Page<TodoSearchHit>> {
+        let limit = pagination.limit();
+        let Some(expression) = match_expression(q) else {
+            return Ok(Page::from_overfetched(vec![], limit, 0));
+        };
+        let hits = sqlx::query_as::<_, TodoSearchHit>(
+            r#"
+select todos.*,
+    -bm25(todos_fts) as rank,
+    highlight(todos_fts, 0, $4, $5) as snippet
+from todos_fts
+join todos on todos.id = todos_fts.rowid
+where todos_fts match $1 and todos.deleted_at is null
+order by bm25(todos_fts), todos.id desc
+limit $2 offset $3;
+        "#,
+        )
+        .bind(&expression)
+        .bind(limit + 1)
+        .bind(pagination.offset())
+        .bind(HIGHLIGHT_START)
+        .bind(HIGHLIGHT_STOP)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let (total,) = sqlx::query_as::<_, (i64,)>(
+            r#"
+select count(*) from todos_fts
+join todos on todos.id = todos_fts.rowid
+where todos_fts match $1 and todos.deleted_at is null;
+        "#,
+        )
+        .bind(&expression)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Page::from_overfetched(hits, limit, total))
+    }
+
+    // 文字のn-gram（FTS5のtrigramトークナイザ）による部分一致検索。
+    // 3文字未満の検索語もLIKEで検索できる。
+    async fn search_ngram(&self, q: &str, pagination: Pagination) -> Result<Page<TodoSearchHit>> {
+        let limit = pagination.limit();
+        let pattern = like_pattern(q);
+        let hits = sqlx::query_as::<_, TodoSearchHit>(
+            r#"
+select todos.*,
+    cast(length($1) as real) / max(length(todos.text), 1) as rank,
+    todos.text as snippet
+from todos_trigram
+join todos on todos.id = todos_trigram.rowid
+where todos_trigram.text like $2 escape '\' and todos.deleted_at is null
+order by rank desc, todos.id desc
+limit $3 offset $4;
+        "#,
+        )
+        .bind(q)
+        .bind(&pattern)
+        .bind(limit + 1)
+        .bind(pagination.offset())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let (total,) = sqlx::query_as::<_, (i64,)>(
+            r#"
+select count(*) from todos_trigram
+join todos on todos.id = todos_trigram.rowid
+where todos_trigram.text like $1 escape '\' and todos.deleted_at is null;
+        "#,
+        )
+        .bind(&pattern)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let hits = hits
+            .into_iter()
+            .map(|hit| TodoSearchHit {
+                snippet: highlight_substring(&hit.snippet, q),
+                ..hit
+            })
+            .collect();
+        Ok(Page::from_overfetched(hits, limit, total))
+    }
+}
+
+#[async_trait]
+impl TodoRepository for TodoRepositoryForSqlite {
+    async fn create(&self, payload: CreateTodo) -> Result<Todo> {
+        let mut tx = self.pool.begin().await?;
+        let todo = self.create_todo(&mut tx, payload).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+    async fn find(&self, id: i32) -> Result<Todo> {
+        let todo = sqlx::query_as::<_, Todo>(
+            r#"
+select * from todos where id=$1 and deleted_at is null
+        "#,
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+            _ => RepositoryError::from(e),
+        })?;
+        Ok(todo)
+    }
+    async fn filter(&self, query: TodoQuery, pagination: Pagination) -> Result<Page<Todo>> {
+        let sort = query.sort();
+        let limit = pagination.limit();
+
+        let mut builder = QueryBuilder::new("select * from todos");
+        push_filters(&mut builder, &query);
+        if let Some(cursor) = pagination.cursor {
+            match sort {
+                TodoSort::IdAsc => builder.push(" and id > "),
+                _ => builder.push(" and id < "),
+            };
+            builder.push_bind(cursor);
+        }
+        builder
+            .push(" order by ")
+            .push(sort.order_by())
+            .push(" limit ")
+            .push_bind(limit + 1)
+            .push(" offset ")
+            .push_bind(pagination.offset());
+        let todos = builder
+            .build_query_as::<Todo>()
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut builder = QueryBuilder::new("select count(*) from todos");
+        push_filters(&mut builder, &query);
+        let (total,) = builder
+            .build_query_as::<(i64,)>()
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(Page::from_overfetched(todos, limit, total))
+    }
+    async fn search(
+        &self,
+        query: SearchQuery,
+        pagination: Pagination,
+    ) -> Result<Page<TodoSearchHit>> {
+        match query.mode.unwrap_or_default() {
+            SearchMode::Words => self.search_words(&query.q, pagination).await,
+            SearchMode::Ngram => self.search_ngram(&query.q, pagination).await,
+        }
+    }
+    async fn update(&self, id: i32, version: Option<i32>, payload: UpdateTodo) -> Result<Todo> {
+        let mut tx = self.pool.begin().await?;
+        let todo = self.update_todo(&mut tx, id, version, payload).await?;
+        tx.commit().await?;
+        Ok(todo)
+    }
+    async fn blockers(&self, id: i32) -> Result<Vec<Todo>> {
+        self.find(id).await?;
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+select todos.* from todos
+join todo_dependencies on todo_dependencies.blocker_id = todos.id
+where todo_dependencies.todo_id=$1 and todos.deleted_at is null
+order by todos.id
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(todos)
+    }
+    async fn add_blocker(&self, id: i32, blocker_id: i32) -> Result<Vec<Todo>> {
+        if id == blocker_id {
+            return Err(blocker_cycle_error().into());
+        }
+        // 別々の依存関係を同時に追加すると、それぞれの確認は通っても合わせて循環することがある。
+        // SQLiteは最初に書き込んだ時に書き込みロックを取るので、先に追加してから確かめて、
+        // 循環していればロールバックする。追加した依存関係は確かめる経路には入らない。
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+insert into todo_dependencies (todo_id, blocker_id)
+select $1, $2 where exists(select 1 from todos where id=$1 and deleted_at is null)
+and exists(select 1 from todos where id=$2 and deleted_at is null)
+on conflict do nothing
+        "#,
+        )
+        .bind(id)
+        .bind(blocker_id)
+        .execute(&mut tx)
+        .await?;
+        self.fetch(&mut tx, id).await?;
+        self.fetch(&mut tx, blocker_id).await?;
+        // blocker_idが既にidにブロックされていると循環する。
+        let (cycle,) = sqlx::query_as::<_, (bool,)>(
+            r#"
+with recursive blocked_by(id) as (
+    select blocker_id from todo_dependencies where todo_id=$1
+    union
+    select todo_dependencies.blocker_id from todo_dependencies
+    join blocked_by on todo_dependencies.todo_id = blocked_by.id
+)
+select exists(select 1 from blocked_by where id=$2)
+        "#,
+        )
+        .bind(blocker_id)
+        .bind(id)
+        .fetch_one(&mut tx)
+        .await?;
+        if cycle {
+            return Err(blocker_cycle_error().into());
+        }
+        tx.commit().await?;
+        self.blockers(id).await
+    }
+    async fn remove_blocker(&self, id: i32, blocker_id: i32) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+delete from todo_dependencies where todo_id=$1 and blocker_id=$2
+        "#,
+        )
+        .bind(id)
+        .bind(blocker_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            self.find(id).await?;
+            return Err(RepositoryError::NotFound(blocker_id).into());
+        }
+        Ok(())
+    }
+    async fn subtree(&self, id: i32) -> Result<Vec<Todo>> {
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+with recursive subtree as (
+    select id from todos where id=$1 and deleted_at is null
+    union
+    select todos.id from todos join subtree on todos.parent_id = subtree.id
+    where todos.deleted_at is null
+)
+select * from todos where id in (select id from subtree) order by id = $1 desc, id
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        if todos.is_empty() {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        Ok(todos)
+    }
+    async fn delete(&self, id: i32, version: Option<i32>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        self.trash_todo(&mut tx, id, version).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+    async fn trash(&self, pagination: Pagination) -> Result<Page<Todo>> {
+        let limit = pagination.limit();
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+select * from todos where deleted_at is not null
+order by deleted_at desc, id desc
+limit $1 offset $2
+        "#,
+        )
+        .bind(limit + 1)
+        .bind(pagination.offset())
+        .fetch_all(&self.pool)
+        .await?;
+
+        let (total,) = sqlx::query_as::<_, (i64,)>(
+            r#"
+select count(*) from todos where deleted_at is not null
+        "#,
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(Page::from_overfetched(todos, limit, total))
+    }
+    async fn restore(&self, id: i32) -> Result<Todo> {
+        let mut tx = self.pool.begin().await?;
+        // 同時に戻されても1回だけ戻すよう、書き込みロックを取ってから読む。
+        self.lock(&mut tx).await?;
+        let todo = sqlx::query_as::<_, Todo>(
+            r#"
+select * from todos where id=$1 and deleted_at is not null
+        "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut tx)
+        .await?
+        .ok_or(RepositoryError::NotFound(id))?;
+        // 親は外部キーで残っていることが保証されているので、ゴミ箱にあるかだけを見る。
+        if let Some(parent_id) = todo.parent_id {
+            let (parent_deleted_at,) = sqlx::query_as::<_, (Option<DateTime<Utc>>,)>(
+                r#"
+select deleted_at from todos where id=$1
+        "#,
+            )
+            .bind(parent_id)
+            .fetch_one(&mut tx)
+            .await?;
+            if parent_deleted_at.is_some() {
+                return Err(trashed_parent_error(id, parent_id).into());
+            }
+        }
+        // 一緒にゴミ箱に入ったサブタスクだけを戻す。
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+with recursive restored as (
+    select id, deleted_at from todos where id=$1 and deleted_at is not null
+    union
+    select todos.id, todos.deleted_at from todos
+    join restored on todos.parent_id = restored.id and todos.deleted_at = restored.deleted_at
+)
+update todos set deleted_at=null, version=version+1
+where id in (select id from restored) and deleted_at is not null
+returning *
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&mut tx)
+        .await?;
+        for todo in &todos {
+            self.record(&mut tx, TodoChange::restored(todo)).await?;
+        }
+        tx.commit().await?;
+        self.find(id).await
+    }
+    async fn purge(&self, id: i32) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        // 外部キーで一緒に削除されるサブタスクも履歴に残す。
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+with recursive purged as (
+    select id from todos where id=$1 and deleted_at is not null
+    union
+    select todos.id from todos join purged on todos.parent_id = purged.id
+)
+select * from todos where id in (select id from purged) order by id
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&mut tx)
+        .await?;
+
+        if todos.is_empty() {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        // サブタスクやタグ、依存関係は外部キーで一緒に削除される。
+        sqlx::query(
+            r#"
+delete from todos where id=$1
+        "#,
+        )
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        for todo in &todos {
+            self.record(&mut tx, TodoChange::purged(todo)).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+    async fn purge_trash(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        // サブタスクが外部キーで先に削除されても履歴に残せるよう、削除する前に読んでおく。
+        let todos = sqlx::query_as::<_, Todo>(
+            r#"
+select * from todos where deleted_at < $1 order by id
+        "#,
+        )
+        .bind(before)
+        .fetch_all(&mut tx)
+        .await?;
+        sqlx::query(
+            r#"
+delete from todos where deleted_at < $1
+        "#,
+        )
+        .bind(before)
+        .execute(&mut tx)
+        .await?;
+        for todo in &todos {
+            self.record(&mut tx, TodoChange::purged(todo)).await?;
+        }
+        tx.commit().await?;
+        Ok(todos.len() as u64)
+    }
+    async fn history(&self, id: i32) -> Result<Vec<TodoEvent>> {
+        let events = sqlx::query_as::<_, TodoEvent>(
+            r#"
+select * from todo_events where todo_id=$1 order by id
+        "#,
+        )
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        if events.is_empty() {
+            self.find(id).await?;
+        }
+        Ok(events)
+    }
+    fn with_actor(&self, actor: Option<String>) -> Self {
+        Self {
+            actor,
+            ..self.clone()
+        }
+    }
+    async fn bulk(&self, operations: Vec<BulkOperation>, atomic: bool) -> Result<Vec<BulkResult>> {
+        let mut tx = self.pool.begin().await?;
+        let mut results = Vec::with_capacity(operations.len());
+        for operation in operations {
+            // 1件ごとにセーブポイントを置き、失敗した操作の途中までの変更を戻す。
+            let mut savepoint = tx.begin().await?;
+            let result = self.execute(&mut savepoint, operation).await;
+            let failed = result.is_err();
+            if failed {
+                savepoint.rollback().await?;
+            } else {
+                savepoint.commit().await?;
+            }
+            results.push(result.map_err(RepositoryError::from));
+            if failed && atomic {
+                tx.rollback().await?;
+                return Ok(results);
+            }
+        }
+        tx.commit().await?;
+        Ok(results)
+    }
+    async fn update_matching(&self, query: TodoQuery, payload: UpdateTodo) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut builder = QueryBuilder::new("select id from todos");
+        push_filters(&mut builder, &query);
+        builder.push(" order by id");
+        let ids = builder
+            .build_query_as::<(i32,)>()
+            .fetch_all(&mut tx)
+            .await?;
+        for (id,) in &ids {
+            self.update_todo(&mut tx, *id, None, payload.clone())
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(ids.len() as u64)
+    }
+    async fn delete_matching(&self, query: TodoQuery) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut builder = QueryBuilder::new("with recursive subtree as (select id from todos");
+        push_filters(&mut builder, &query);
+        builder
+            .push(
+                " union select todos.id from todos join subtree on todos.parent_id = subtree.id \
+                 where todos.deleted_at is null) update todos set deleted_at = ",
+            )
+            .push_bind(Utc::now())
+            .push(
+                ", version = version + 1 where id in (select id from subtree) and deleted_at is null \
+                 returning *",
+            );
+        let todos = builder.build_query_as::<Todo>().fetch_all(&mut tx).await?;
+        for todo in &todos {
+            self.record(&mut tx, TodoChange::deleted(todo)).await?;
+        }
+        tx.commit().await?;
+        Ok(todos.len() as u64)
+    }
+}
+
+#[async_trait]
+impl TagRepository for TodoRepositoryForSqlite {
+    async fn create_tag(&self, payload: CreateTag) -> Result<Tag> {
+        self.check_tag_name(&payload.name, None).await?;
+        let tag = sqlx::query_as::<_, Tag>(
+            r#"
+insert into tags (name) values ($1)
+returning *
+        "#,
+        )
+        .bind(payload.name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(tag)
+    }
+    async fn find_tag(&self, id: i32) -> Result<Tag> {
+        let tag = sqlx::query_as::<_, Tag>(
+            r#"
+select * from tags where id=$1
+        "#,
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+            _ => RepositoryError::from(e),
+        })?;
+        Ok(tag)
+    }
+    async fn all_tags(&self) -> Result<Vec<Tag>> {
+        let tags = sqlx::query_as::<_, Tag>(
+            r#"
+select * from tags order by name
+        "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tags)
+    }
+    async fn update_tag(&self, id: i32, payload: UpdateTag) -> Result<Tag> {
+        self.check_tag_name(&payload.name, Some(id)).await?;
+        let tag = sqlx::query_as::<_, Tag>(
+            r#"
+update tags set name=$1
+where id=$2
+returning *
+        "#,
+        )
+        .bind(payload.name)
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+            _ => RepositoryError::from(e),
+        })?;
+        Ok(tag)
+    }
+    async fn delete_tag(&self, id: i32) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+delete from tags where id=$1
+        "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        Ok(())
+    }
+    async fn tags_of(&self, todo_id: i32) -> Result<Vec<Tag>> {
+        self.find(todo_id).await?;
+        let tags = sqlx::query_as::<_, Tag>(
+            r#"
+select tags.* from tags
+join todo_tags on todo_tags.tag_id = tags.id
+where todo_tags.todo_id=$1
+order by tags.name
+        "#,
+        )
+        .bind(todo_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(tags)
+    }
+    async fn attach_tag(&self, todo_id: i32, tag_id: i32) -> Result<Vec<Tag>> {
+        // 外部キー違反ではなくNotFoundを返すため、先に存在を確認する。
+        self.find(todo_id).await?;
+        self.find_tag(tag_id).await?;
+        sqlx::query(
+            r#"
+insert into todo_tags (todo_id, tag_id) values ($1, $2)
+on conflict do nothing
+        "#,
+        )
+        .bind(todo_id)
+        .bind(tag_id)
+        .execute(&self.pool)
+        .await?;
+        self.tags_of(todo_id).await
+    }
+    async fn detach_tag(&self, todo_id: i32, tag_id: i32) -> Result<()> {
+        let result = sqlx::query(
+            r#"
+delete from todo_tags where todo_id=$1 and tag_id=$2
+        "#,
+        )
+        .bind(todo_id)
+        .bind(tag_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            self.find(todo_id).await?;
+            return Err(RepositoryError::NotFound(tag_id).into());
+        }
+        Ok(())
+    }
+}
+
+// リストと件数をまとめて取得するselect句
+const SELECT_LISTS: &str = r#"
+select lists.id, lists.name,
+    (select count(*) from todos where todos.list_id = lists.id and todos.deleted_at is null
+        and not todos.completed) as open_count,
+    (select count(*) from todos where todos.list_id = lists.id and todos.deleted_at is null
+        and todos.completed) as done_count
+from lists
+"#;
+
+#[async_trait]
+impl ListRepository for TodoRepositoryForSqlite {
+    async fn create_list(&self, payload: CreateList) -> Result<TodoList> {
+        let (id,) = sqlx::query_as::<_, (i32,)>(
+            r#"
+insert into lists (name) values ($1)
+returning id
+        "#,
+        )
+        .bind(payload.name)
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.find_list(id).await
+    }
+    async fn find_list(&self, id: i32) -> Result<TodoList> {
+        let list = sqlx::query_as::<_, TodoList>(&format!("{SELECT_LISTS} where lists.id=$1"))
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| match e {
+                sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+                _ => RepositoryError::from(e),
+            })?;
+        Ok(list)
+    }
+    async fn all_lists(&self) -> Result<Vec<TodoList>> {
+        let lists = sqlx::query_as::<_, TodoList>(&format!("{SELECT_LISTS} order by lists.id"))
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(lists)
+    }
+    async fn update_list(&self, id: i32, payload: UpdateList) -> Result<TodoList> {
+        let result = sqlx::query(
+            r#"
+update lists set name=$1
+where id=$2
+        "#,
+        )
+        .bind(payload.name)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        self.find_list(id).await
+    }
+    async fn delete_list(&self, id: i32, policy: DeletePolicy) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        match policy {
+            DeletePolicy::Restrict => {
+                let (count,) = sqlx::query_as::<_, (i64,)>(
+                    r#"
+select count(*) from todos where list_id=$1 and deleted_at is null
+        "#,
+                )
+                .bind(id)
+                .fetch_one(&mut tx)
+                .await?;
+                if count > 0 {
+                    return Err(RepositoryError::Conflict(format!(
+                        "list {id} still has {count} todos"
+                    ))
+                    .into());
+                }
+            }
+            DeletePolicy::Cascade => {
+                let todos = sqlx::query_as::<_, Todo>(
+                    r#"
+with recursive subtree as (
+    select id from todos where list_id=$1 and deleted_at is null
+    union
+    select todos.id from todos join subtree on todos.parent_id = subtree.id
+    where todos.deleted_at is null
+)
+update todos set deleted_at=$2, version=version+1
+where id in (select id from subtree) and deleted_at is null
+returning *
+        "#,
+                )
+                .bind(id)
+                .bind(Utc::now())
+                .fetch_all(&mut tx)
+                .await?;
+                for todo in &todos {
+                    self.record(&mut tx, TodoChange::deleted(todo)).await?;
+                }
+            }
+        }
+        // ゴミ箱のTodoはリストから外す。
+        sqlx::query(
+            r#"
+update todos set list_id=null, version=version+1 where list_id=$1
+        "#,
+        )
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+        let result = sqlx::query(
+            r#"
+delete from lists where id=$1
+        "#,
+        )
+        .bind(id)
+        .execute(&mut tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id).into());
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdempotencyRepository for TodoRepositoryForSqlite {
+    async fn reserve_key(&self, key: &str, request_hash: &str) -> Result<Option<IdempotencyKey>> {
+        let mut tx = self.pool.begin().await?;
+        let now = Utc::now();
+        // 保存期間を過ぎたキーは無いものとして予約し直す。
+        sqlx::query(
+            r#"
+delete from idempotency_keys where key=$1 and created_at < $2
+        "#,
+        )
+        .bind(key)
+        .bind(now - self.idempotency_ttl)
+        .execute(&mut tx)
+        .await?;
+        let reserved = sqlx::query(
+            r#"
+insert into idempotency_keys (key, request_hash, created_at) values ($1, $2, $3)
+on conflict (key) do nothing
+returning key
+        "#,
+        )
+        .bind(key)
+        .bind(request_hash)
+        .bind(now)
+        .fetch_optional(&mut tx)
+        .await?
+        .is_some();
+        let existing = if reserved {
+            None
+        } else {
+            let existing = sqlx::query_as::<_, IdempotencyKey>(
+                r#"
+select * from idempotency_keys where key=$1
+        "#,
+            )
+            .bind(key)
+            .fetch_one(&mut tx)
+            .await?;
+            Some(existing)
+        };
+        tx.commit().await?;
+        Ok(existing)
+    }
+    async fn save_response(&self, key: &str, status: u16, body: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+update idempotency_keys set status=$2, body=$3 where key=$1
+        "#,
+        )
+        .bind(key)
+        .bind(i32::from(status))
+        .bind(body)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn release_key(&self, key: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+delete from idempotency_keys where key=$1 and status is null
+        "#,
+        )
+        .bind(key)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+    async fn purge_keys(&self) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+delete from idempotency_keys where created_at < $1
+        "#,
+        )
+        .bind(Utc::now() - self.idempotency_ttl)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+// 絞り込み条件をwhere句として追加する。値は全てバインドする。
+fn push_filters(builder: &mut QueryBuilder<'_, Sqlite>, query: &TodoQuery) {
+    builder.push(" where deleted_at is null");
+    if let Some(completed) = query.completed {
+        builder.push(" and completed = ").push_bind(completed);
+    }
+    if let Some(priority) = query.priority {
+        builder.push(" and priority = ").push_bind(priority);
+    }
+    if let Some(priority) = query.min_priority {
+        builder.push(" and priority >= ").push_bind(priority);
+    }
+    if let Some(list_id) = query.list_id {
+        builder.push(" and list_id = ").push_bind(list_id);
+    }
+    if let Some(parent_id) = query.parent_id {
+        builder.push(" and parent_id = ").push_bind(parent_id);
+    }
+    for (column, op, value) in query.date_ranges() {
+        if let Some(value) = value {
+            builder
+                .push(format!(" and {column} {op} "))
+                .push_bind(value);
+        }
+    }
+    if let Some(q) = &query.q {
+        builder
+            .push(" and text like ")
+            .push_bind(like_pattern(q))
+            .push(r" escape '\'");
+    }
+    if let Some(tag) = &query.tag {
+        builder
+            .push(
+                " and exists (select 1 from todo_tags join tags on tags.id = todo_tags.tag_id \
+                 where todo_tags.todo_id = todos.id and tags.name = ",
+            )
+            .push_bind(tag.clone())
+            .push(")");
+    }
+}
+
+// FTS5のMATCH式を作る。構文として解釈されないように、単語ごとにダブルクォートで囲む。
+fn match_expression(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::{
+        handler,
+        repositories::{Priority, Progress, TodoEventKind},
+    };
+    use actix_web::{
+        http::{header::ContentType, StatusCode},
+        test, web, App,
+    };
+    use chrono::{DateTime, Duration};
+    use pretty_assertions::assert_eq;
+    use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+    // インメモリのSQLiteは接続ごとに別のDBになるため、接続を1つに絞る。
+    pub async fn memory_repository() -> TodoRepositoryForSqlite {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("failed connect sqlite.");
+        let repository = TodoRepositoryForSqlite::new(pool);
+        repository.migrate().await.expect("failed migrate sqlite.");
+        repository
+    }
+
+    // 同時に書き込めるよう、接続を分けられるファイルのDBを使う。
+    async fn file_repository(name: &str) -> TodoRepositoryForSqlite {
+        let path = std::env::temp_dir().join(format!("todo_{name}_{}.db", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(2)
+            .connect_with(options)
+            .await
+            .expect("failed connect sqlite.");
+        let repository = TodoRepositoryForSqlite::new(pool);
+        repository.migrate().await.expect("failed migrate sqlite.");
+        repository
+    }
+
+    #[actix_web::test]
+    async fn crud_scenario() {
+        let repository = memory_repository().await;
+        let text = "todo test".to_string();
+
+        //create : Todoを作成
+        let expected = repository
+            .create(CreateTodo::new(text.clone()))
+            .await
+            .expect("failed create todo.");
+        assert_eq!(1, expected.id);
+        assert_eq!(text, expected.text);
+        assert!(!expected.completed);
+        assert_eq!(expected.created_at, expected.updated_at);
+        assert_eq!(None, expected.completed_at);
+
+        //find　：Todo idを取得
+        let todo = repository.find(expected.id).await.unwrap();
+        assert_eq!(expected, todo);
+
+        //all　全てのTodoを取得
+        let todos = repository
+            .all(Pagination::default())
+            .await
+            .expect("failed get all todo.");
+        assert_eq!(vec![expected.clone()], todos.items);
+
+        // update　： Todoを更新。完了にするとcompleted_atが入る
+        let todo = repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    text: Some("update todo text".to_string()),
+                    completed: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed update todo.");
+        assert_eq!(
+            Todo {
+                text: "update todo text".to_string(),
+                completed: true,
+                updated_at: todo.updated_at,
+                completed_at: todo.completed_at,
+                version: 2,
+                ..expected.clone()
+            },
+            todo
+        );
+        assert!(todo.updated_at >= expected.updated_at);
+        assert_eq!(Some(todo.updated_at), todo.completed_at);
+
+        // 未完了に戻すとcompleted_atが消える
+        let todo = repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    completed: Some(false),
+                    ..Default::default()
+                },
+            )
+            .await
+            .expect("failed update todo.");
+        assert_eq!(None, todo.completed_at);
+        assert_eq!(expected.created_at, todo.created_at);
+
+        // delete　：Todoを削除
+        repository
+            .delete(1, None)
+            .await
+            .expect("failed delete todo.");
+        assert!(repository.find(1).await.is_err());
+        assert!(repository.delete(1, None).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn should_filter_and_sort() {
+        let repository = memory_repository().await;
+        for text in ["buy milk", "Milk tea", "bread", "100% juice"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let ids = |page: Page<Todo>| page.items.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let query = TodoQuery {
+            completed: Some(false),
+            q: Some("milk".to_string()),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(1, page.total);
+        assert_eq!(vec![2], ids(page));
+
+        let query = TodoQuery {
+            q: Some("%".to_string()),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![4], ids(page));
+
+        let query = TodoQuery {
+            sort: Some(TodoSort::TextAsc),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![4, 2, 3, 1], ids(page));
+
+        // 未完了のものは最後に並ぶ
+        let query = TodoQuery {
+            sort: Some(TodoSort::CompletedAtDesc),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![1, 4, 3, 2], ids(page));
+
+        let completed_at = repository.find(1).await.unwrap().completed_at;
+        let query = TodoQuery {
+            completed_after: completed_at,
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![1], ids(page));
+
+        let query = TodoQuery {
+            sort: Some(TodoSort::IdAsc),
+            ..Default::default()
+        };
+        let pagination = Pagination {
+            limit: Some(2),
+            cursor: Some(1),
+            ..Default::default()
+        };
+        let page = repository.filter(query, pagination).await.unwrap();
+        assert_eq!(Some(3), page.next_cursor);
+        assert_eq!(vec![2, 3], ids(page));
+    }
+
+    #[actix_web::test]
+    async fn should_search_todos() {
+        let repository = memory_repository().await;
+        for text in ["buy milk", "milk and milk tea", "bread"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        // 更新後の本文で検索できる
+        repository
+            .update(
+                3,
+                None,
+                UpdateTodo {
+                    text: Some("bread and Milk".to_string()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let page = repository
+            .search(
+                SearchQuery {
+                    q: "milk".to_string(),
+                    mode: None,
+                },
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(3, page.total);
+        assert_eq!(2, page.items[0].todo.id);
+        assert_eq!(
+            "<mark>milk</mark> and <mark>milk</mark> tea",
+            page.items[0].snippet
+        );
+
+        let page = repository
+            .search(
+                SearchQuery {
+                    q: "\"bread milk".to_string(),
+                    mode: None,
+                },
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![3],
+            page.items.iter().map(|h| h.todo.id).collect::<Vec<_>>()
+        );
+
+        repository.delete(3, None).await.unwrap();
+        let page = repository
+            .search(
+                SearchQuery {
+                    q: "bread".to_string(),
+                    mode: None,
+                },
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(0, page.total);
+    }
+
+    #[actix_web::test]
+    async fn should_search_japanese_by_ngram() {
+        let repository = memory_repository().await;
+        for text in ["牛乳を買う", "パンを買う", "Buy MILK"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        let search = |q: &str| SearchQuery {
+            q: q.to_string(),
+            mode: Some(SearchMode::Ngram),
+        };
+
+        let page = repository
+            .search(search("牛乳"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(1, page.total);
+        assert_eq!("<mark>牛乳</mark>を買う", page.items[0].snippet);
+
+        let page = repository
+            .search(search("を買う"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(2, page.total);
+
+        let page = repository
+            .search(search("milk"), Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!("Buy <mark>MILK</mark>", page.items[0].snippet);
+    }
+
+    #[actix_web::test]
+    async fn should_find_due_todos() {
+        let repository = memory_repository().await;
+        let now = Utc::now();
+        for (text, due_at) in [
+            ("overdue", Some(now - Duration::hours(1))),
+            ("tomorrow", Some(now + Duration::days(1))),
+            ("next month", Some(now + Duration::days(30))),
+            ("no due date", None),
+            ("yesterday", Some(now - Duration::days(1))),
+        ] {
+            repository
+                .create(CreateTodo {
+                    due_at,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+        let ids = |page: Page<Todo>| page.items.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let page = repository
+            .overdue(now, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![5, 1], ids(page));
+
+        let page = repository
+            .upcoming(now, 7, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![2], ids(page));
+
+        let todo = repository
+            .update(
+                2,
+                None,
+                UpdateTodo {
+                    due_at: Some(None),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(None, todo.due_at);
+    }
+
+    #[actix_web::test]
+    async fn should_filter_and_sort_by_priority() {
+        let repository = memory_repository().await;
+        for priority in [Priority::Low, Priority::Urgent, Priority::None] {
+            repository
+                .create(CreateTodo {
+                    priority,
+                    ..CreateTodo::new(format!("{priority:?}"))
+                })
+                .await
+                .unwrap();
+        }
+        let ids = |page: Page<Todo>| page.items.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let query = TodoQuery {
+            sort: Some(TodoSort::PriorityDesc),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![2, 1, 3], ids(page));
+
+        let query = TodoQuery {
+            min_priority: Some(Priority::Low),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(vec![2, 1], ids(page));
+
+        let todo = repository.find(2).await.unwrap();
+        assert_eq!(Priority::Urgent, todo.priority);
+    }
+
+    #[actix_web::test]
+    async fn should_attach_tags() {
+        let repository = memory_repository().await;
+        for text in ["buy milk", "write report"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        let work = repository
+            .create_tag(CreateTag::new("work".to_string()))
+            .await
+            .unwrap();
+        let home = repository
+            .create_tag(CreateTag::new("home".to_string()))
+            .await
+            .unwrap();
+        let conflict = repository
+            .create_tag(CreateTag::new("work".to_string()))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(conflict),
+            RepositoryError::Conflict(message) if message == "tag [work] already exists"
+        ));
+        // 制約違反のメッセージにはスキーマを含めない
+        let conflict = sqlx::query("insert into tags (name) values ('work')")
+            .execute(&repository.pool)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(conflict),
+            RepositoryError::Conflict(message) if message == "conflicts with existing data"
+        ));
+
+        repository.attach_tag(1, work.id).await.unwrap();
+        // 同じタグを2回付けても1つだけ
+        repository.attach_tag(1, work.id).await.unwrap();
+        let tags = repository.attach_tag(1, home.id).await.unwrap();
+        assert_eq!(vec![home.clone(), work.clone()], tags);
+        repository.attach_tag(2, work.id).await.unwrap();
+
+        let query = TodoQuery {
+            tag: Some("work".to_string()),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(2, page.total);
+
+        // Todoを消すとタグとの関連も消える
+        repository.delete(1, None).await.unwrap();
+        let query = TodoQuery {
+            tag: Some("home".to_string()),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(0, page.total);
+
+        repository.delete_tag(work.id).await.unwrap();
+        assert!(repository.tags_of(2).await.unwrap().is_empty());
+        let err = repository.attach_tag(2, work.id).await.unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(err),
+            RepositoryError::NotFound(_)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn should_count_and_delete_lists() {
+        let repository = memory_repository().await;
+        let work = repository
+            .create_list(CreateList::new("work".to_string()))
+            .await
+            .unwrap();
+        for text in ["write report", "review"] {
+            repository
+                .create(CreateTodo {
+                    list_id: Some(work.id),
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+        repository
+            .create(CreateTodo::new("buy milk".to_string()))
+            .await
+            .unwrap();
+        repository
+            .update(
+                1,
+                None,
+                UpdateTodo {
+                    completed: Some(true),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+
+        let list = repository.find_list(work.id).await.unwrap();
+        assert_eq!((1, 1), (list.open_count, list.done_count));
+
+        let err = repository
+            .delete_list(work.id, DeletePolicy::Restrict)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(err),
+            RepositoryError::Conflict(_)
+        ));
+
+        repository
+            .delete_list(work.id, DeletePolicy::Cascade)
+            .await
+            .unwrap();
+        let page = repository.all(Pagination::default()).await.unwrap();
+        assert_eq!(vec![3], page.items.iter().map(|t| t.id).collect::<Vec<_>>());
+        assert!(repository.all_lists().await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn should_manage_subtasks() {
+        let repository = memory_repository().await.with_auto_complete_parent(true);
+        for (text, parent_id) in [
+            ("release", None),
+            ("write notes", Some(1)),
+            ("proofread", Some(2)),
+            ("tag version", Some(1)),
+        ] {
+            repository
+                .create(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+
+        let ids = |todos: Vec<Todo>| todos.iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3], ids(repository.subtree(2).await.unwrap()));
+        assert_eq!(vec![1, 2, 3, 4], ids(repository.subtree(1).await.unwrap()));
+
+        let err = repository
+            .update(
+                2,
+                None,
+                UpdateTodo {
+                    parent_id: Some(Some(3)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(err),
+            RepositoryError::Validation(_)
+        ));
+
+        for id in [3, 4] {
+            repository
+                .update(
+                    id,
+                    None,
+                    UpdateTodo {
+                        completed: Some(true),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        assert!(repository.find(1).await.unwrap().completed);
+        assert_eq!(
+            Progress { done: 2, total: 2 },
+            repository.progress(1).await.unwrap()
+        );
+
+        repository.delete(1, None).await.unwrap();
+        let page = repository.all(Pagination::default()).await.unwrap();
+        assert_eq!(0, page.total);
+
+        // 古いTodoを新しいTodoのサブタスクにしても、先頭は指定したTodo
+        for text in ["write draft", "publish"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        repository
+            .update(
+                5,
+                None,
+                UpdateTodo {
+                    parent_id: Some(Some(6)),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(vec![6, 5], ids(repository.subtree(6).await.unwrap()));
+    }
+
+    #[actix_web::test]
+    async fn should_not_create_cycle_by_concurrent_blockers() {
+        let repository = file_repository("blockers").await;
+        for text in ["deploy", "run tests"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+
+        let (a, b) = tokio::join!(repository.add_blocker(1, 2), repository.add_blocker(2, 1));
+        assert!(a.is_ok() != b.is_ok());
+        let blockers = repository.blockers(1).await.unwrap().len()
+            + repository.blockers(2).await.unwrap().len();
+        assert_eq!(1, blockers);
+    }
+
+    #[actix_web::test]
+    async fn should_block_completion_by_dependencies() {
+        let repository = memory_repository().await;
+        for text in ["deploy", "run tests", "review"] {
+            repository
+                .create(CreateTodo::new(text.to_string()))
+                .await
+                .unwrap();
+        }
+        repository.add_blocker(1, 2).await.unwrap();
+        repository.add_blocker(2, 3).await.unwrap();
+        // 同じ依存関係を2回追加しても1つだけ
+        let blockers = repository.add_blocker(1, 2).await.unwrap();
+        assert_eq!(1, blockers.len());
+
+        let err = repository.add_blocker(3, 1).await.unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(err),
+            RepositoryError::Validation(_)
+        ));
+
+        let complete = UpdateTodo {
+            completed: Some(true),
+            ..Default::default()
+        };
+        let err = repository
+            .update(2, None, complete.clone())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RepositoryError::from(err),
+            RepositoryError::Conflict(_)
+        ));
+        repository.update(3, None, complete.clone()).await.unwrap();
+        repository.update(2, None, complete).await.unwrap();
+
+        // Todoを消すと依存関係も消える
+        repository.delete(2, None).await.unwrap();
+        assert!(repository.blockers(1).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn should_spawn_next_occurrence_of_recurring_todo() {
+        let repository = memory_repository().await;
+        let due_at = DateTime::parse_from_rfc3339("2026-03-27T09:00:00+09:00")
+            .unwrap()
+            .with_timezone(&Utc);
+        let todo = repository
+            .create(CreateTodo {
+                due_at: Some(due_at),
+                recurrence: Some("FREQ=MONTHLY;BYDAY=-1FR".to_string()),
+                time_zone: Some("Asia/Tokyo".to_string()),
+                ..CreateTodo::new("pay rent".to_string())
+            })
+            .await
+            .unwrap();
+
+        // 繰り返しをやめると次は作られない
+        let update = UpdateTodo {
+            recurrence: Some(None),
+            ..Default::default()
+        };
+        let stopped = repository.update(todo.id, None, update).await.unwrap();
+        assert_eq!(None, stopped.recurrence);
+        let update = UpdateTodo {
+            recurrence: Some(Some("FREQ=MONTHLY;BYDAY=-1FR".to_string())),
+            completed: Some(true),
+            ..Default::default()
+        };
+        repository.update(todo.id, None, update).await.unwrap();
+
+        let query = TodoQuery {
+            completed: Some(false),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(1, page.items.len());
+        let next = &page.items[0];
+        assert_eq!(
+            DateTime::parse_from_rfc3339("2026-04-24T09:00:00+09:00").unwrap(),
+            next.due_at.unwrap()
+        );
+        assert_eq!(Some("FREQ=MONTHLY;BYDAY=-1FR"), next.recurrence.as_deref());
+        assert_eq!(Some("Asia/Tokyo"), next.time_zone.as_deref());
+    }
+
+    #[actix_web::test]
+    async fn should_auto_complete_parent_of_recurring_subtask() {
+        let repository = memory_repository().await.with_auto_complete_parent(true);
+        let due_at = DateTime::parse_from_rfc3339("2026-10-19T09:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for (text, parent_id) in [("weekly review", None), ("clean inbox", Some(1))] {
+            repository
+                .create(CreateTodo {
+                    due_at: Some(due_at),
+                    parent_id,
+                    recurrence: Some("FREQ=WEEKLY".to_string()),
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+
+        // 次の回を作る前に親を完了にし、親の次の回も作る
+        let update = UpdateTodo {
+            completed: Some(true),
+            ..Default::default()
+        };
+        repository.update(2, None, update).await.unwrap();
+        assert!(repository.find(1).await.unwrap().completed);
+        let query = TodoQuery {
+            completed: Some(false),
+            ..Default::default()
+        };
+        let page = repository
+            .filter(query, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![("weekly review", None), ("clean inbox", Some(1))],
+            page.items
+                .iter()
+                .map(|todo| (todo.text.as_str(), todo.parent_id))
+                .collect::<Vec<_>>()
+        );
+        for todo in &page.items {
+            assert_eq!(Some(due_at + Duration::weeks(1)), todo.due_at);
+        }
+    }
+
+    #[actix_web::test]
+    async fn should_keep_deleted_todos_in_trash() {
+        let repository = memory_repository().await;
+        let list = repository
+            .create_list(CreateList::new("house".to_string()))
+            .await
+            .unwrap();
+        for (text, parent_id) in [("clean room", None), ("clean desk", Some(1))] {
+            repository
+                .create(CreateTodo {
+                    list_id: Some(list.id),
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+        let search = || {
+            repository.search(
+                SearchQuery {
+                    q: "clean".to_string(),
+                    mode: None,
+                },
+                Pagination::default(),
+            )
+        };
+        assert_eq!(2, search().await.unwrap().total);
+
+        // 先にサブタスクを削除してから親を削除する
+        repository.delete(2, None).await.unwrap();
+        repository.delete(1, None).await.unwrap();
+        assert!(repository.delete(1, None).await.is_err());
+        assert_eq!(0, search().await.unwrap().total);
+        assert_eq!(0, repository.find_list(list.id).await.unwrap().open_count);
+        let trash = repository.trash(Pagination::default()).await.unwrap();
+        assert_eq!(2, trash.total);
+        assert_eq!(Some(1), trash.items.first().map(|todo| todo.id));
+
+        // 親を戻しても、先に削除したサブタスクはゴミ箱に残る
+        let todo = repository.restore(1).await.unwrap();
+        assert_eq!(None, todo.deleted_at);
+        assert_eq!(1, repository.subtree(1).await.unwrap().len());
+        assert_eq!(1, repository.find_list(list.id).await.unwrap().open_count);
+        let restored = repository.restore(2).await.unwrap();
+        assert_eq!(Some(1), restored.parent_id);
+
+        // リストをcascadeで削除するとTodoはゴミ箱に入り、リストからは外れる
+        repository
+            .delete_list(list.id, DeletePolicy::Cascade)
+            .await
+            .unwrap();
+        let trash = repository.trash(Pagination::default()).await.unwrap();
+        assert_eq!(2, trash.total);
+        assert!(trash.items.iter().all(|todo| todo.list_id.is_none()));
+
+        // 期限より前にゴミ箱に入ったものだけを完全に削除する
+        let now = Utc::now();
+        assert_eq!(
+            0,
+            repository
+                .purge_trash(now - Duration::days(30))
+                .await
+                .unwrap()
+        );
+        assert!(
+            repository
+                .purge_trash(now + Duration::seconds(1))
+                .await
+                .unwrap()
+                > 0
+        );
+        assert_eq!(
+            0,
+            repository.trash(Pagination::default()).await.unwrap().total
+        );
+        assert!(repository.restore(1).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn should_trash_and_restore_once_when_concurrent() {
+        let repository = file_repository("trash_once").await;
+        for (text, parent_id) in [("release", None), ("write notes", Some(1))] {
+            repository
+                .create(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+
+        let deletes: Vec<_> = (0..8)
+            .map(|_| {
+                let repository = repository.clone();
+                tokio::spawn(async move { repository.delete(1, None).await })
+            })
+            .collect();
+        let mut deleted = 0;
+        for delete in deletes {
+            match delete.await.unwrap() {
+                Ok(()) => deleted += 1,
+                Err(e) => assert!(matches!(e.into(), RepositoryError::NotFound(1))),
+            }
+        }
+        assert_eq!(1, deleted);
+
+        let restores: Vec<_> = (0..8)
+            .map(|_| {
+                let repository = repository.clone();
+                tokio::spawn(async move { repository.restore(1).await })
+            })
+            .collect();
+        let mut restored = 0;
+        for restore in restores {
+            match restore.await.unwrap() {
+                Ok(_) => restored += 1,
+                Err(e) => assert!(matches!(e.into(), RepositoryError::NotFound(1))),
+            }
+        }
+        assert_eq!(1, restored);
+
+        for id in [1, 2] {
+            let kinds = repository
+                .history(id)
+                .await
+                .unwrap()
+                .iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                vec![
+                    TodoEventKind::Created,
+                    TodoEventKind::Deleted,
+                    TodoEventKind::Restored,
+                ],
+                kinds
+            );
+            assert_eq!(3, repository.find(id).await.unwrap().version);
+        }
+    }
+
+    #[actix_web::test]
+    async fn should_record_history_with_mutations() {
+        let repository = memory_repository()
+            .await
+            .with_auto_complete_parent(true)
+            .with_actor(Some("alice".to_string()));
+        for (text, parent_id) in [("release", None), ("write notes", Some(1)), ("tag", None)] {
+            repository
+                .create(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+        repository.add_blocker(2, 3).await.unwrap();
+        let completed = || UpdateTodo {
+            completed: Some(true),
+            ..Default::default()
+        };
+
+        // 失敗した変更は履歴に残らない
+        assert!(repository.update(2, None, completed()).await.is_err());
+        assert_eq!(1, repository.history(2).await.unwrap().len());
+
+        // 自動で完了にした親も履歴に残る
+        repository.update(3, None, completed()).await.unwrap();
+        repository.update(2, None, completed()).await.unwrap();
+        let events = repository.history(1).await.unwrap();
+        let kinds = events.iter().map(|event| event.kind).collect::<Vec<_>>();
+        assert_eq!(vec![TodoEventKind::Created, TodoEventKind::Updated], kinds);
+        assert_eq!(Some(false), events[1].old_completed);
+        assert_eq!(Some(true), events[1].new_completed);
+        assert_eq!(Some("alice"), events[1].actor.as_deref());
+
+        // 完全に削除したサブタスクの履歴も残る
+        repository.delete(1, None).await.unwrap();
+        repository
+            .with_actor(None)
+            .purge_trash(Utc::now() + Duration::seconds(1))
+            .await
+            .unwrap();
+        let events = repository.history(2).await.unwrap();
+        let kinds = events.iter().map(|event| event.kind).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                TodoEventKind::Created,
+                TodoEventKind::Updated,
+                TodoEventKind::Deleted,
+                TodoEventKind::Purged,
+            ],
+            kinds
+        );
+        assert_eq!(None, events[3].actor);
+        assert_eq!(Some("write notes"), events[3].old_text.as_deref());
+        assert!(repository.history(4).await.is_err());
+
+        // 履歴は書き換えられない
+        assert!(sqlx::query("update todo_events set actor='mallory'")
+            .execute(&repository.pool)
+            .await
+            .is_err());
+        assert!(sqlx::query("delete from todo_events")
+            .execute(&repository.pool)
+            .await
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn should_reject_stale_version() {
+        let repository = memory_repository().await;
+        let todo = repository
+            .create(CreateTodo::new("write draft".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(1, todo.version);
+        let update = || UpdateTodo {
+            text: Some("write article".to_string()),
+            ..Default::default()
+        };
+        let todo = repository.update(todo.id, Some(1), update()).await.unwrap();
+        assert_eq!(2, todo.version);
+
+        let err = repository.update(todo.id, Some(1), update()).await;
+        assert!(matches!(
+            err.map_err(RepositoryError::from),
+            Err(RepositoryError::PreconditionFailed(1))
+        ));
+        let err = repository.delete(todo.id, Some(1)).await;
+        assert!(matches!(
+            err.map_err(RepositoryError::from),
+            Err(RepositoryError::PreconditionFailed(1))
+        ));
+        let err = repository.delete(2, Some(1)).await;
+        assert!(matches!(
+            err.map_err(RepositoryError::from),
+            Err(RepositoryError::NotFound(2))
+        ));
+
+        // ゴミ箱に入れて戻してもversionは進む
+        repository.delete(todo.id, Some(2)).await.unwrap();
+        let todo = repository.restore(todo.id).await.unwrap();
+        assert_eq!(4, todo.version);
+    }
+
+    #[actix_web::test]
+    async fn should_apply_concurrent_updates_in_turn() {
+        let repository = file_repository("updates_in_turn").await;
+        repository
+            .create(CreateTodo::new("deploy".to_string()))
+            .await
+            .unwrap();
+
+        // If-Matchが無い変更は、同時に来ても412にせず順に適用する。
+        let updates: Vec<_> = (0..6)
+            .map(|i| {
+                let repository = repository.clone();
+                tokio::spawn(async move {
+                    let payload = UpdateTodo {
+                        text: Some(format!("deploy {i}")),
+                        ..Default::default()
+                    };
+                    repository.update(1, None, payload).await
+                })
+            })
+            .collect();
+        for update in updates {
+            update.await.unwrap().unwrap();
+        }
+        assert_eq!(7, repository.find(1).await.unwrap().version);
+        assert_eq!(7, repository.history(1).await.unwrap().len());
+    }
+
+    #[actix_web::test]
+    async fn should_run_bulk_in_transaction() {
+        let repository = memory_repository().await;
+        let todo = repository
+            .create(CreateTodo::new("write draft".to_string()))
+            .await
+            .unwrap();
+        let operations = || {
+            vec![
+                BulkOperation::Create {
+                    todo: CreateTodo::new("publish".to_string()),
+                },
+                BulkOperation::Update {
+                    id: todo.id,
+                    version: Some(1),
+                    todo: UpdateTodo {
+                        text: Some("write article".to_string()),
+                        ..Default::default()
+                    },
+                },
+                BulkOperation::Delete {
+                    id: 99,
+                    version: None,
+                },
+                BulkOperation::Delete {
+                    id: todo.id,
+                    version: None,
+                },
+            ]
+        };
+
+        // atomicなら失敗した所で止めて、全て取り消す
+        let results = repository.bulk(operations(), true).await.unwrap();
+        assert_eq!(3, results.len());
+        assert!(matches!(results[2], Err(RepositoryError::NotFound(99))));
+        let page = repository.all(Pagination::default()).await.unwrap();
+        assert_eq!(vec![todo.clone()], page.items);
+        assert!(repository.history(2).await.is_err());
+
+        // atomicでなければ失敗した操作だけを取り消す
+        let results = repository.bulk(operations(), false).await.unwrap();
+        assert_eq!(4, results.len());
+        let created = results[0].as_ref().unwrap().as_ref().unwrap();
+        assert_eq!("publish", created.text);
+        assert_eq!(2, results[1].as_ref().unwrap().as_ref().unwrap().version);
+        assert!(matches!(results[2], Err(RepositoryError::NotFound(99))));
+        assert_eq!(&None, results[3].as_ref().unwrap());
+        let page = repository.all(Pagination::default()).await.unwrap();
+        assert_eq!(
+            vec!["publish"],
+            page.items.iter().map(|todo| &todo.text).collect::<Vec<_>>()
+        );
+        let kinds: Vec<_> = repository
+            .history(todo.id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            vec![
+                TodoEventKind::Created,
+                TodoEventKind::Updated,
+                TodoEventKind::Deleted
+            ],
+            kinds
+        );
+    }
+
+    #[actix_web::test]
+    async fn should_update_and_delete_matching() {
+        let repository = memory_repository().await;
+        for (text, parent_id) in [
+            ("book hotel", None),
+            ("book flight", None),
+            ("pack", None),
+            ("pack camera", Some(3)),
+        ] {
+            repository
+                .create(CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string())
+                })
+                .await
+                .unwrap();
+        }
+        repository.add_blocker(2, 3).await.unwrap();
+        let query = |q: &str| TodoQuery {
+            q: Some(q.to_string()),
+            ..Default::default()
+        };
+        let complete = || UpdateTodo {
+            completed: Some(true),
+            ..Default::default()
+        };
+
+        // 1件でも失敗すれば全て戻す
+        let err = repository.update_matching(query("book"), complete()).await;
+        assert!(matches!(
+            err.map_err(RepositoryError::from),
+            Err(RepositoryError::Conflict(_))
+        ));
+        assert!(!repository.find(1).await.unwrap().completed);
+
+        let affected = repository
+            .update_matching(query("pack"), complete())
+            .await
+            .unwrap();
+        assert_eq!(2, affected);
+        assert_eq!(2, repository.find(3).await.unwrap().version);
+
+        // 条件に合うサブタスクと親は重複して数えない
+        let affected = repository
+            .delete_matching(TodoQuery {
+                completed: Some(true),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(2, affected);
+        let page = repository.all(Pagination::default()).await.unwrap();
+        assert_eq!(
+            vec![2, 1],
+            page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+        );
+        let kinds: Vec<_> = repository
+            .history(4)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            vec![
+                TodoEventKind::Created,
+                TodoEventKind::Updated,
+                TodoEventKind::Deleted
+            ],
+            kinds
+        );
+    }
+
+    #[actix_web::test]
+    async fn should_store_idempotency_keys() {
+        let repository = memory_repository().await;
+        assert_eq!(
+            None,
+            repository.reserve_key("retry-1", "hash").await.unwrap()
+        );
+        let reserved = repository
+            .reserve_key("retry-1", "other")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ("hash", None),
+            (reserved.request_hash.as_str(), reserved.status)
+        );
+
+        // レスポンスを保存したキーは消さない
+        repository
+            .save_response("retry-1", 201, r#"{"id":1}"#)
+            .await
+            .unwrap();
+        repository.release_key("retry-1").await.unwrap();
+        let saved = repository
+            .reserve_key("retry-1", "hash")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(201), saved.status);
+        assert_eq!(Some(r#"{"id":1}"#.to_string()), saved.body);
+
+        repository.reserve_key("retry-2", "hash").await.unwrap();
+        repository.release_key("retry-2").await.unwrap();
+        assert_eq!(
+            None,
+            repository.reserve_key("retry-2", "other").await.unwrap()
+        );
+
+        // 保存期間を過ぎたキーは無いものとして扱う
+        assert_eq!(0, repository.purge_keys().await.unwrap());
+        let repository = repository.with_idempotency_ttl(Duration::zero());
+        assert_eq!(
+            None,
+            repository.reserve_key("retry-1", "other").await.unwrap()
+        );
+        assert_eq!(2, repository.purge_keys().await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn should_reserve_key_once_when_concurrent() {
+        let repository = file_repository("reserve_once").await;
+        let reserves: Vec<_> = (0..8)
+            .map(|_| {
+                let repository = repository.clone();
+                tokio::spawn(async move { repository.reserve_key("retry-1", "hash").await })
+            })
+            .collect();
+        let mut reserved = 0;
+        for reserve in reserves {
+            if reserve.await.unwrap().unwrap().is_none() {
+                reserved += 1;
+            }
+        }
+        assert_eq!(1, reserved);
+    }
+
+    #[actix_web::test]
+    async fn should_return_created_todo_when_response_is_not_saved() {
+        let repository = memory_repository().await;
+        // レスポンスの保存だけ失敗させる
+        sqlx::query(
+            r#"
+create trigger idempotency_keys_no_update before update on idempotency_keys
+begin
+    select raise(abort, 'disk is full');
+end
+        "#,
+        )
+        .execute(&repository.pool)
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(repository.clone()))
+                .configure(handler::config::<TodoRepositoryForSqlite>),
+        )
+        .await;
+        let create = || {
+            test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .insert_header((handler::IDEMPOTENCY_KEY, "retry-1"))
+                .set_json(CreateTodo::new("buy milk".to_string()))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, create()).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        let todo: Todo = test::read_body_json(resp).await;
+        assert_eq!("buy milk", todo.text);
+
+        // キーは処理中のまま残るので、リトライしてもTodoは増えない
+        let resp = test::call_service(&app, create()).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+        let page = repository.all(Pagination::default()).await.unwrap();
+        assert_eq!(1, page.total);
+    }
+
+    #[actix_web::test]
+    async fn should_return_create_error_when_key_is_not_released() {
+        let repository = memory_repository().await;
+        // キーを消すのだけ失敗させる
+        sqlx::query(
+            r#"
+create trigger idempotency_keys_no_delete before delete on idempotency_keys
+begin
+    select raise(abort, 'disk is full');
+end
+        "#,
+        )
+        .execute(&repository.pool)
+        .await
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(repository.clone()))
+                .configure(handler::config::<TodoRepositoryForSqlite>),
+        )
+        .await;
+        let create = || {
+            test::TestRequest::post()
+                .uri("/todos")
+                .insert_header(ContentType::json())
+                .insert_header((handler::IDEMPOTENCY_KEY, "retry-1"))
+                .set_json(CreateTodo {
+                    list_id: Some(9),
+                    ..CreateTodo::new("buy milk".to_string())
+                })
+                .to_request()
+        };
+
+        // キーを消せなくても、リストが無いことを返す
+        let resp = test::call_service(&app, create()).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+
+        // キーは処理中のまま残る
+        let resp = test::call_service(&app, create()).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+    }
+}